@@ -0,0 +1,49 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes128Gcm, Aes256Gcm,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+
+pub const TAG_LEN: usize = 16;
+pub const NONCE_LEN: usize = 12;
+
+// aead ciphers shared by the shadowsocks and vmess chunk streams
+pub enum AeadCipher {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+impl AeadCipher {
+    pub fn aes_128_gcm(key: &[u8]) -> Self {
+        Self::Aes128Gcm(Box::new(Aes128Gcm::new(key.into())))
+    }
+
+    pub fn aes_256_gcm(key: &[u8]) -> Self {
+        Self::Aes256Gcm(Box::new(Aes256Gcm::new(key.into())))
+    }
+
+    pub fn chacha20_poly1305(key: &[u8]) -> Self {
+        Self::ChaCha20Poly1305(Box::new(ChaCha20Poly1305::new(key.into())))
+    }
+
+    pub fn encrypt(&self, nonce: &[u8], msg: &[u8]) -> std::io::Result<Vec<u8>> {
+        let payload = Payload { msg, aad: &[] };
+        match self {
+            Self::Aes128Gcm(c) => c.encrypt(nonce.into(), payload),
+            Self::Aes256Gcm(c) => c.encrypt(nonce.into(), payload),
+            Self::ChaCha20Poly1305(c) => c.encrypt(nonce.into(), payload),
+        }
+        .map_err(|e| std::io::Error::other(e.to_string()))
+    }
+
+    pub fn decrypt(&self, nonce: &[u8], msg: &[u8]) -> std::io::Result<Vec<u8>> {
+        let payload = Payload { msg, aad: &[] };
+        match self {
+            Self::Aes128Gcm(c) => c.decrypt(nonce.into(), payload),
+            Self::Aes256Gcm(c) => c.decrypt(nonce.into(), payload),
+            Self::ChaCha20Poly1305(c) => c.decrypt(nonce.into(), payload),
+        }
+        .map_err(|e| std::io::Error::other(e.to_string()))
+    }
+}
@@ -0,0 +1,86 @@
+use super::unix_timestamp;
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use worker::*;
+
+// seconds between kv refreshes of an isolate-local copy
+pub const REFRESH_INTERVAL: u64 = 300;
+
+// isolate-local copy of a kv-backed setting and the time it was loaded
+pub struct IsolateCache<T> {
+    loaded: Mutex<Option<(u64, Arc<T>)>>,
+}
+
+impl<T> IsolateCache<T> {
+    pub const fn new() -> Self {
+        Self {
+            loaded: Mutex::new(None),
+        }
+    }
+
+    // the cached value, unless it is older than the refresh interval
+    pub fn fresh(&self, now: u64) -> Option<Arc<T>> {
+        match self.loaded.lock().unwrap().as_ref() {
+            Some((loaded, value)) if now.saturating_sub(*loaded) < REFRESH_INTERVAL => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    // the cached value regardless of its age
+    pub fn last(&self) -> Option<Arc<T>> {
+        self.loaded.lock().unwrap().as_ref().map(|(_, value)| value.clone())
+    }
+
+    pub fn store(&self, now: u64, value: Arc<T>) {
+        *self.loaded.lock().unwrap() = Some((now, value));
+    }
+}
+
+impl<T: Default> IsolateCache<T> {
+    // a failed fetch keeps the last good value, or the default
+    pub async fn load(&self, name: &str, fetch: impl Future<Output = Result<T>>) -> Arc<T> {
+        let (value, err) = self.refresh(unix_timestamp(), fetch).await;
+        if let Some(e) = err {
+            console_error!("[{}]: keeping the last good value: {}", name, e);
+        }
+        value
+    }
+
+    async fn refresh(&self, now: u64, fetch: impl Future<Output = Result<T>>) -> (Arc<T>, Option<Error>) {
+        if let Some(value) = self.fresh(now) {
+            return (value, None);
+        }
+
+        let (value, err) = match fetch.await {
+            Ok(value) => (Arc::new(value), None),
+            Err(e) => (self.last().unwrap_or_default(), Some(e)),
+        };
+        self.store(now, value.clone());
+        (value, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    #[test]
+    fn test_refresh() {
+        let cache: IsolateCache<u32> = IsolateCache::new();
+        let refresh = |now, fetch: Result<u32>| {
+            cache
+                .refresh(now, async { fetch })
+                .now_or_never()
+                .map(|(value, err)| (*value, err.is_some()))
+                .unwrap()
+        };
+
+        assert_eq!(refresh(0, Err(Error::RustError("down".into()))), (0, true));
+        assert_eq!(refresh(1, Ok(7)), (0, false));
+        assert_eq!(refresh(REFRESH_INTERVAL, Ok(7)), (7, false));
+        assert_eq!(refresh(REFRESH_INTERVAL * 2, Err(Error::RustError("down".into()))), (7, true));
+        assert_eq!(refresh(REFRESH_INTERVAL * 2 + 1, Ok(9)), (7, false));
+    }
+}
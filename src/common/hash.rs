@@ -40,8 +40,8 @@ impl RecursiveHash {
         let mut ipad = [0u8; 64];
         let mut opad = [0u8; 64];
 
-        ipad[..key.len()].copy_from_slice(&key);
-        opad[..key.len()].copy_from_slice(&key);
+        ipad[..key.len()].copy_from_slice(key);
+        opad[..key.len()].copy_from_slice(key);
 
         for b in ipad.iter_mut() {
             *b ^= 0x36;
@@ -68,8 +68,8 @@ impl Hasher for RecursiveHash {
     fn clone(&self) -> Box<dyn Hasher> {
         let inner = self.inner.clone();
         let outer = self.outer.clone();
-        let ipad = self.ipad.clone();
-        let opad = self.opad.clone();
+        let ipad = self.ipad;
+        let opad = self.opad;
 
         Box::new(Self {
             inner,
@@ -84,10 +84,10 @@ impl Hasher for RecursiveHash {
     }
 
     fn finalize(&mut self) -> [u8; 32] {
-        let result: [u8; 32] = self.inner.finalize().into();
+        let result: [u8; 32] = self.inner.finalize();
         self.outer.update(&self.opad);
         self.outer.update(&result);
-        self.outer.finalize().into()
+        self.outer.finalize()
     }
 }
 
@@ -97,7 +97,7 @@ pub fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
         Box::new(Sha256Hash::new()),
     ));
 
-    for p in path.into_iter() {
+    for p in path.iter() {
         current = Box::new(RecursiveHash::new(p, current));
     }
 
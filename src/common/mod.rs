@@ -1,9 +1,14 @@
+pub mod aead;
+pub mod cache;
+pub mod cidr;
 pub mod hash;
+pub mod replay;
 
 use std::net::{Ipv4Addr, Ipv6Addr};
 use tokio::io::{AsyncRead, AsyncReadExt};
 use worker::*;
 
+pub const KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY: &[u8] = b"AES Auth ID Encryption";
 pub const KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY: &[u8] =
     b"VMess Header AEAD Key_Length";
 pub const KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV: &[u8] =
@@ -41,6 +46,10 @@ macro_rules! sha256 {
     }
 }
 
+pub fn unix_timestamp() -> u64 {
+    Date::now().as_millis() / 1000
+}
+
 pub async fn parse_addr<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Result<String> {
     // combined addr type between Vmess, VLESS, and Trojan.
     // VLESS wouldn't connect to ipv6 address due to mismatch addr type
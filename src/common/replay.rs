@@ -0,0 +1,58 @@
+use std::collections::{HashSet, VecDeque};
+
+// bounded, time-limited set of recently seen handshake identifiers
+pub struct ReplayFilter {
+    capacity: usize,
+    ttl: u64,
+    order: VecDeque<(u64, Vec<u8>)>,
+    seen: HashSet<Vec<u8>>,
+}
+
+impl ReplayFilter {
+    pub fn new(capacity: usize, ttl: u64) -> Self {
+        Self {
+            capacity,
+            ttl,
+            order: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+        }
+    }
+
+    // returns false if `key` was already seen within the ttl
+    pub fn check(&mut self, key: &[u8], now: u64) -> bool {
+        while let Some((timestamp, _)) = self.order.front() {
+            if now.saturating_sub(*timestamp) <= self.ttl && self.order.len() < self.capacity {
+                break;
+            }
+            if let Some((_, k)) = self.order.pop_front() {
+                self.seen.remove(&k);
+            }
+        }
+
+        if !self.seen.insert(key.to_vec()) {
+            return false;
+        }
+        self.order.push_back((now, key.to_vec()));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let mut filter = ReplayFilter::new(2, 10);
+        assert!(filter.check(b"a", 100));
+        assert!(!filter.check(b"a", 105));
+        // expired
+        assert!(filter.check(b"a", 111));
+
+        // over capacity, the oldest key is forgotten
+        assert!(filter.check(b"b", 112));
+        assert!(filter.check(b"c", 113));
+        assert!(!filter.check(b"c", 113));
+        assert!(filter.check(b"a", 114));
+    }
+}
@@ -1,13 +1,273 @@
+use crate::cloudflare::CloudflareRanges;
+use crate::dns::filter::DnsFilter;
+use crate::policy::DestinationPolicy;
+use crate::pool::Strategy;
+use crate::route::RouteTable;
+use crate::common::cache::IsolateCache;
+use crate::common::unix_timestamp;
+
+use std::fmt;
+use std::sync::Arc;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
 use uuid::Uuid;
+use worker::kv::KvStore;
+use worker::*;
+
+static USERS: IsolateCache<Vec<User>> = IsolateCache::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Vless,
+    Trojan,
+    Vmess,
+    Shadowsocks,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Protocol::Vless => "vless",
+            Protocol::Trojan => "trojan",
+            Protocol::Vmess => "vmess",
+            Protocol::Shadowsocks => "shadowsocks",
+        };
+        f.write_str(name)
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct User {
+    pub id: Uuid,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // empty means every protocol is allowed
+    #[serde(default)]
+    pub protocols: Vec<Protocol>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl User {
+    pub fn allows(&self, protocol: Protocol) -> bool {
+        self.enabled && (self.protocols.is_empty() || self.protocols.contains(&protocol))
+    }
+
+    // password based protocols fall back to the uuid string
+    pub fn password(&self) -> String {
+        self.password.clone().unwrap_or_else(|| self.id.to_string())
+    }
+}
+
+#[derive(Clone)]
+pub struct DnsConfig {
+    pub upstreams: Vec<String>,
+    // per-query timeout in milliseconds
+    pub timeout: u64,
+    // query every upstream at once instead of falling back in order
+    pub race: bool,
+    pub filter: Arc<DnsFilter>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            upstreams: vec!["https://1.1.1.1/dns-query".to_string(), "https://8.8.8.8/dns-query".to_string()],
+            timeout: 5000,
+            race: false,
+            filter: Arc::default(),
+        }
+    }
+}
+
+impl DnsConfig {
+    pub async fn load(env: &Env) -> Self {
+        let upstreams = env
+            .var("DOH_UPSTREAMS")
+            .map(|x| x.to_string())
+            .unwrap_or_else(|_| "https://1.1.1.1/dns-query,https://8.8.8.8/dns-query".to_string())
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+        let timeout = env
+            .var("DOH_TIMEOUT")
+            .ok()
+            .and_then(|x| x.to_string().parse().ok())
+            .unwrap_or(5000);
+        let race = env
+            .var("DOH_STRATEGY")
+            .map(|x| x.to_string() == "race")
+            .unwrap_or(false);
+
+        let filter = DnsFilter::load(env).await;
 
+        Self {
+            upstreams,
+            timeout,
+            race,
+            filter,
+        }
+    }
+}
+
+// the users, dns, routes, policy, cloudflare ranges and strategy start out
+// empty and are filled in by `load` on the routes that need them
+#[derive(Default)]
 pub struct Config {
-    pub uuid: Uuid,
+    pub users: Arc<Vec<User>>,
+    pub dns: DnsConfig,
+    pub routes: Arc<RouteTable>,
+    pub policy: DestinationPolicy,
+    pub cloudflare: Arc<CloudflareRanges>,
     pub host: String,
-    pub proxy_addr: String,
-    pub proxy_port: u16,
+    // proxy ips in the order they are tried, `host:443` unless the tunnel
+    // path picks others
+    pub proxies: Vec<(String, u16)>,
+    // persists proxy ip health when set
+    pub health_kv: Option<KvStore>,
+    pub strategy: Strategy,
+    // CF-Connecting-IP of the tunnel request
+    pub client_ip: Option<String>,
 
     pub main_page_url: String,
     pub link_page_url: String,
     pub sub_page_url: String,
-    
+}
+
+impl Config {
+    // every setting falls back to its default when malformed, so a bad kv
+    // value or env var cannot take the worker down
+    pub async fn load(&mut self, env: &Env) {
+        self.users = load_users(env).await;
+        self.dns = DnsConfig::load(env).await;
+        self.routes = RouteTable::load(env).await;
+        self.policy = DestinationPolicy::from_env(env);
+        self.cloudflare = CloudflareRanges::load(env).await;
+        self.strategy = match env.var("PROXY_STRATEGY") {
+            Ok(x) => x.to_string().parse().unwrap_or_else(|e| {
+                console_error!("[pool]: ignoring PROXY_STRATEGY: {}", e);
+                Strategy::default()
+            }),
+            Err(_) => Strategy::default(),
+        };
+        self.health_kv = match env.var("PROXY_HEALTH_KV") {
+            Ok(x) if x.to_string() == "true" => env.kv("SIREN").ok(),
+            _ => None,
+        };
+    }
+
+    pub fn users_for(&self, protocol: Protocol) -> impl Iterator<Item = &User> {
+        self.users.iter().filter(move |user| user.allows(protocol))
+    }
+
+    // an enabled user's id or password
+    pub fn user_by_token(&self, token: &str) -> Option<&User> {
+        self.users.iter().filter(|user| user.enabled).find(|user| {
+            let id = bool::from(user.id.to_string().as_bytes().ct_eq(token.as_bytes()));
+            let password = bool::from(user.password().as_bytes().ct_eq(token.as_bytes()));
+            id | password
+        })
+    }
+
+    // the user named by a `:token` path segment, or else the `token` query
+    // parameter
+    pub fn user_by_request(&self, param: Option<&str>, url: &Url) -> Option<&User> {
+        let token = match param {
+            Some(token) => token.to_string(),
+            None => url.query_pairs().find(|(k, _)| k == "token")?.1.to_string(),
+        };
+        self.user_by_token(&token)
+    }
+}
+
+// user table lookup order: `users` key in SIREN kv, `USERS` env var, then the
+// single UUID credential. The table is cached per isolate, and a malformed
+// one keeps the last good table, or the single UUID credential.
+pub async fn load_users(env: &Env) -> Arc<Vec<User>> {
+    let now = unix_timestamp();
+    if let Some(users) = USERS.fresh(now) {
+        return users;
+    }
+
+    let users = match fetch_users(env).await {
+        Ok(Some(users)) => Arc::new(users),
+        Ok(None) => Arc::new(default_users(env)),
+        Err(e) => {
+            console_error!("[users]: keeping the last good user table: {}", e);
+            USERS.last().unwrap_or_else(|| Arc::new(default_users(env)))
+        }
+    };
+    USERS.store(now, users.clone());
+    users
+}
+
+async fn fetch_users(env: &Env) -> Result<Option<Vec<User>>> {
+    let mut users_str = env.kv("SIREN")?.get("users").text().await?.unwrap_or_default();
+    if users_str.is_empty() {
+        users_str = env.var("USERS").map(|x| x.to_string()).unwrap_or_default();
+    }
+    if users_str.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&users_str)?))
+}
+
+// the `UUID` and `TROJAN_PASSWORD` credential, none when UUID is unset or
+// invalid
+fn default_users(env: &Env) -> Vec<User> {
+    let uuid = env.var("UUID").map(|x| x.to_string()).unwrap_or_default();
+    let Ok(id) = Uuid::parse_str(&uuid) else {
+        console_error!("[users]: no user table and no valid UUID");
+        return Vec::new();
+    };
+
+    vec![User {
+        id,
+        label: "default".to_string(),
+        enabled: true,
+        protocols: Vec::new(),
+        password: env.var("TROJAN_PASSWORD").map(|x| x.to_string()).ok(),
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_by_request() {
+        let user = |id: &str, enabled| User {
+            id: id.parse().unwrap(),
+            label: String::new(),
+            enabled,
+            protocols: Vec::new(),
+            password: Some(format!("{}-password", &id[..4])),
+        };
+        let config = Config {
+            users: Arc::new(vec![
+                user("11111111-1111-1111-1111-111111111111", true),
+                user("22222222-2222-2222-2222-222222222222", false),
+            ]),
+            ..Default::default()
+        };
+        let url = |query: &str| Url::parse(&format!("https://example.com/dns-query?{}", query)).unwrap();
+        let user_id = |param, query| config.user_by_request(param, &url(query)).map(|user| user.id.to_string());
+        let first = Some("11111111-1111-1111-1111-111111111111".to_string());
+
+        assert_eq!(user_id(Some("11111111-1111-1111-1111-111111111111"), ""), first);
+        assert_eq!(user_id(None, "token=1111-password"), first);
+        // the path segment wins over the query
+        assert_eq!(user_id(Some("nope"), "token=1111-password"), None);
+        assert_eq!(user_id(None, "token=22222222-2222-2222-2222-222222222222"), None);
+        assert_eq!(user_id(None, "token=2222-password"), None);
+        assert_eq!(user_id(None, "dns=AAAB"), None);
+    }
 }
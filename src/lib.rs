@@ -2,36 +2,34 @@ mod common;
 mod config;
 mod proxy;
 
-use crate::config::Config;
+use crate::config::{load_users, Config, DnsConfig, Protocol};
 use crate::proxy::*;
 
-use std::collections::HashMap;
-use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE}, Engine as _};
 use serde_json::json;
-use uuid::Uuid;
 use worker::*;
 use once_cell::sync::Lazy;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
 use regex::Regex;
 
 static PROXYIP_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"^.+-\d+$").unwrap());
-static PROXYKV_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"^([A-Z]{2})").unwrap());
 
 #[event(fetch)]
 async fn main(req: Request, env: Env, _: Context) -> Result<Response> {
-    let uuid = env
-        .var("UUID")
-        .map(|x| Uuid::parse_str(&x.to_string()).unwrap_or_default())?;
     let host = req.url()?.host().map(|x| x.to_string()).unwrap_or_default();
     let main_page_url = env.var("MAIN_PAGE_URL").map(|x|x.to_string()).unwrap();
     let link_page_url = env.var("LINK_PAGE_URL").map(|x|x.to_string()).unwrap();
     let sub_page_url = env.var("SUB_PAGE_URL").map(|x|x.to_string()).unwrap();
-    let config = Config { uuid, host: host.clone(), proxy_addr: host, proxy_port: 443, main_page_url, link_page_url, sub_page_url };
+    let config = Config { host: host.clone(), proxies: vec![(host, 443)], main_page_url, link_page_url, sub_page_url, ..Default::default() };
 
     Router::with_data(config)
         .on_async("/", fe)
         .on_async("/link", link)
         .on_async("/sub", sub)
-        .on("/v2r", v2r)
+        .on_async("/v2r", v2r)
+        .on_async("/v2r/:token", v2r)
+        .on_async("/dns-query", dns_query)
+        .on_async("/dns-query/:token", dns_query)
         .on_async("/Stupid-World/:proxyip", tunnel)
         .run(req, env)
         .await
@@ -55,46 +53,128 @@ async fn sub(_: Request, cx: RouteContext<Config>) -> Result<Response> {
     get_response_from_url(cx.data.sub_page_url).await
 }
 
-async fn tunnel(req: Request, mut cx: RouteContext<Config>) -> Result<Response> {
-    let mut proxyip = cx.param("proxyip").unwrap().to_string();
-    if PROXYKV_PATTERN.is_match(&proxyip)  {
-        let kvid_list: Vec<String> = proxyip.split(",").map(|s|s.to_string()).collect();
-        let kv = cx.kv("SIREN")?;
-        let mut proxy_kv_str = kv.get("proxy_kv").text().await?.unwrap_or("".to_string());
-        let mut rand_buf = [0u8, 1];
-        getrandom::getrandom(&mut rand_buf).expect("failed generating random number");
-        
-        if proxy_kv_str.len() == 0 {
-            console_log!("getting proxy kv from github...");
-            let req = Fetch::Url(Url::parse("https://raw.githubusercontent.com/FoolVPN-ID/Nautica/refs/heads/main/kvProxyList.json")?);
-            let mut res = req.send().await?;
-            if res.status_code() == 200 {
-                proxy_kv_str = res.text().await?.to_string();
-                kv.put("proxy_kv", &proxy_kv_str)?.expiration_ttl(60 * 60 * 24).execute().await?; // 24 hours
-            } else {
-                return Err(Error::from(format!("error getting proxy kv: {}", res.status_code())));
+// https://datatracker.ietf.org/doc/html/rfc8484#section-4.1
+//
+// only for users, who pass their id or password as the last path segment or
+// the `token` parameter
+async fn dns_query(mut req: Request, mut cx: RouteContext<Config>) -> Result<Response> {
+    cx.data.users = load_users(&cx.env).await;
+    if cx.data.user_by_request(cx.param("token").map(|x| x.as_str()), &req.url()?).is_none() {
+        return Response::error("Not Found", 404);
+    }
+
+    let query = match req.method() {
+        Method::Get => match dns::get_query(&req.url()?) {
+            Ok(query) => query,
+            Err(e) => return Response::error(e.to_string(), 400),
+        },
+        Method::Post => {
+            if !dns::is_dns_message(req.headers().get("content-type")?.as_deref()) {
+                return Response::error("unsupported content type", 415);
             }
+            req.bytes().await?
+        }
+        _ => return Response::error("method not allowed", 405),
+    };
+    if dns::check_query(&query).is_err() {
+        return Response::error("malformed dns message", 400);
+    }
+
+    cx.data.dns = DnsConfig::load(&cx.env).await;
+    let answer = dns::doh(&cx.data.dns, &query).await?;
+    let max_age = dns::message::ttl_offsets(&answer)
+        .ok()
+        .and_then(|offsets| offsets.iter().map(|(_, ttl)| *ttl).min())
+        .unwrap_or(0);
+
+    let mut headers = Headers::new();
+    headers.set("content-type", "application/dns-message")?;
+    headers.set("cache-control", &format!("max-age={max_age}"))?;
+    Ok(Response::from_bytes(answer)?.with_headers(headers))
+}
+
+async fn tunnel(req: Request, mut cx: RouteContext<Config>) -> Result<Response> {
+    // selection queries may carry escaped separators or isp names with spaces
+    let mut proxyip = match percent_decode_str(cx.param("proxyip").unwrap()).decode_utf8() {
+        Ok(proxyip) => proxyip.to_string(),
+        Err(_) => return Response::error("invalid proxy ip selection", 400),
+    };
+    let mut proxies = Vec::new();
+    cx.data.load(&cx.env).await;
+    if let Some((_, strategy)) = req.url()?.query_pairs().find(|(k, _)| k == "strategy") {
+        match strategy.parse() {
+            Ok(strategy) => cx.data.strategy = strategy,
+            Err(e) => return Response::error(e.to_string(), 400),
+        }
+    }
+    cx.data.client_ip = req.headers().get("CF-Connecting-IP")?;
+    let client_ip = cx.data.client_ip.as_deref();
+
+    if let Some(kv) = cx.data.health_kv.as_ref() {
+        if let Err(e) = pool::restore(kv).await {
+            console_error!("[pool]: restoring proxy health: {}", e);
         }
-        
-        let proxy_kv: HashMap<String, Vec<String>> = serde_json::from_str(&proxy_kv_str)?;
-        
-        // select random KV ID
-        let kv_index = (rand_buf[0] as usize) % kvid_list.len();
-        proxyip = kvid_list[kv_index].clone();
-        
-        // select random proxy ip
-        let proxyip_index = (rand_buf[0] as usize) % proxy_kv[&proxyip].len();
-        proxyip = proxy_kv[&proxyip][proxyip_index].clone().replace(":", "-");
+    }
+
+    if proxyip.eq_ignore_ascii_case("auto") {
+        let proxy_list = list::ProxyList::load(&cx.env).await?;
+        let now = common::unix_timestamp();
+        let countries = geo::Location::from_request(&req).nearest(&proxy_list.country_codes(), |country| {
+            let health = pool::PROXY_HEALTH.lock().unwrap();
+            proxy_list
+                .proxies(country, None)
+                .iter()
+                .any(|(addr, port)| health.is_available(addr, *port, now))
+        });
+        if countries.is_empty() {
+            return Response::error("no healthy proxy ip available", 503);
+        }
+
+        // every proxy ip of the nearest countries, in strategy order
+        let entries = countries
+            .iter()
+            .flat_map(|country| proxy_list.proxies(country, None))
+            .collect();
+        proxies = cx.data.strategy.order(&countries.join(","), client_ip, entries);
+    } else if !PROXYIP_PATTERN.is_match(&proxyip) {
+        // anything else but an explicit ip-port is a selection query over
+        // the proxy list, see query::SelectionQuery
+        let query: query::SelectionQuery = match proxyip.parse() {
+            Ok(query) => query,
+            Err(e) => return Response::error(e.to_string(), 400),
+        };
+        let proxy_list = list::ProxyList::load(&cx.env).await?;
+        let kvid_list = match proxy_list.select(&query) {
+            Ok(countries) => countries,
+            Err(e) => return Response::error(e, 404),
+        };
+
+        // select KV ID
+        let weights: Vec<u32> = kvid_list.iter().map(|(_, weight)| *weight).collect();
+        let kv_index = cx.data.strategy.start_weighted(&proxyip, client_ip, &weights);
+        let country = &kvid_list[kv_index].0;
+
+        // order the country's proxy ips, the first one is used and the rest
+        // follow as failover candidates
+        let entries = proxy_list.proxies(country, query.isp.as_deref());
+        proxies = cx.data.strategy.order(country, client_ip, entries);
+    }
+    if let Some((addr, port)) = proxies.first() {
+        proxyip = format!("{}-{}", addr, port);
     }
 
     let upgrade = req.headers().get("Upgrade")?.unwrap_or_default();
-    if upgrade == "websocket".to_string() && PROXYIP_PATTERN.is_match(&proxyip) {
-        if let Some((addr, port_str)) = proxyip.split_once('-') {
-            if let Ok(port) = port_str.parse() {
-                cx.data.proxy_addr = addr.to_string();
-                cx.data.proxy_port = port;
+    if upgrade == "websocket" && PROXYIP_PATTERN.is_match(&proxyip) {
+        if proxies.is_empty() {
+            if let Some((addr, port_str)) = proxyip.split_once('-') {
+                if let Ok(port) = port_str.parse() {
+                    proxies.push((addr.to_string(), port));
+                }
             }
         }
+        if !proxies.is_empty() {
+            cx.data.proxies = proxies;
+        }
         
         let WebSocketPair { server, client } = WebSocketPair::new()?;
         server.accept()?;
@@ -113,19 +193,32 @@ async fn tunnel(req: Request, mut cx: RouteContext<Config>) -> Result<Response>
 
 }
 
-fn v2r(_: Request, cx: RouteContext<Config>) -> Result<Response> {
+// links for every enabled user, one per protocol the user may use
+// links for the user whose id or password is given as the token
+async fn v2r(req: Request, mut cx: RouteContext<Config>) -> Result<Response> {
+    cx.data.users = load_users(&cx.env).await;
+    let Some(user) = cx.data.user_by_request(cx.param("token").map(|x| x.as_str()), &req.url()?) else {
+        return Response::error("Not Found", 404);
+    };
+
     let host = cx.data.host.to_string();
-    let uuid = cx.data.uuid.to_string();
+    let mut links = Vec::new();
+    let uuid = user.id.to_string();
+    let password = user.password();
+    let name = |protocol: &str| match user.label.as_str() {
+        "" => format!("siren {protocol}"),
+        label => format!("siren {protocol} {label}"),
+    };
 
-    let vmess_v2r = {
+    if user.allows(Protocol::Vmess) {
         let config = json!({
-            "ps": "siren vmess",
+            "ps": name("vmess"),
             "v": "2",
             "add": host,
             "port": "80",
             "id": uuid,
             "aid": "0",
-            "scy": "zero",
+            "scy": "auto",
             "net": "ws",
             "type": "none",
             "host": host,
@@ -134,11 +227,21 @@ fn v2r(_: Request, cx: RouteContext<Config>) -> Result<Response> {
             "sni": "",
             "alpn": ""}
         );
-        format!("vmess://{}", URL_SAFE.encode(config.to_string()))
-    };
-    let vless_v2r = format!("vless://{uuid}@{host}:443?encryption=none&type=ws&host={host}&path=%2FKR&security=tls&sni={host}#siren vless");
-    let trojan_v2r = format!("trojan://{uuid}@{host}:443?encryption=none&type=ws&host={host}&path=%2FKR&security=tls&sni={host}#siren trojan");
-    let ss_v2r = format!("ss://{}@{host}:443?plugin=v2ray-plugin%3Btls%3Bmux%3D0%3Bmode%3Dwebsocket%3Bpath%3D%2FKR%3Bhost%3D{host}#siren ss", URL_SAFE.encode(format!("none:{uuid}")));
-    
-    Response::from_body(ResponseBody::Body(format!("{vmess_v2r}\n{vless_v2r}\n{trojan_v2r}\n{ss_v2r}").into()))
+        links.push(format!("vmess://{}", URL_SAFE.encode(config.to_string())));
+    }
+    if user.allows(Protocol::Vless) {
+        links.push(format!("vless://{uuid}@{host}:443?encryption=none&type=ws&host={host}&path=%2FKR&security=tls&sni={host}#{}", name("vless")));
+    }
+    if user.allows(Protocol::Trojan) {
+        links.push(format!("trojan://{password}@{host}:443?encryption=none&type=ws&host={host}&path=%2FKR&security=tls&sni={host}#{}", name("trojan")));
+    }
+    if user.allows(Protocol::Shadowsocks) {
+        links.push(format!("ss://{}@{host}:443?plugin=v2ray-plugin%3Btls%3Bmux%3D0%3Bmode%3Dwebsocket%3Bpath%3D%2FKR%3Bhost%3D{host}#{}", URL_SAFE.encode(format!("aes-256-gcm:{password}")), name("ss")));
+        // sip002 takes aead-2022 userinfo as percent-encoded plain text, not base64url
+        let psk = STANDARD.encode(shadowsocks::derive_psk(&password, 32));
+        let psk = utf8_percent_encode(&psk, NON_ALPHANUMERIC);
+        links.push(format!("ss://2022-blake3-aes-256-gcm:{psk}@{host}:443?plugin=v2ray-plugin%3Btls%3Bmux%3D0%3Bmode%3Dwebsocket%3Bpath%3D%2FKR%3Bhost%3D{host}#{}", name("ss2022")));
+    }
+
+    Response::from_body(ResponseBody::Body(links.join("\n").into()))
 }
@@ -0,0 +1,147 @@
+use crate::common::cidr::Cidr;
+use crate::common::cache::IsolateCache;
+use crate::config::DnsConfig;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use futures_util::future::{select, Either};
+use futures_util::pin_mut;
+use worker::*;
+
+// https://www.cloudflare.com/ips-v4 and https://www.cloudflare.com/ips-v6,
+// overridden by a json array under the `cloudflare_ranges` key in SIREN kv
+const CLOUDFLARE_RANGES: &[&str] = &[
+    "173.245.48.0/20",
+    "103.21.244.0/22",
+    "103.22.200.0/22",
+    "103.31.4.0/22",
+    "141.101.64.0/18",
+    "108.162.192.0/18",
+    "190.93.240.0/20",
+    "188.114.96.0/20",
+    "197.234.240.0/22",
+    "198.41.128.0/17",
+    "162.158.0.0/15",
+    "104.16.0.0/13",
+    "104.24.0.0/14",
+    "172.64.0.0/13",
+    "131.0.72.0/22",
+    "2400:cb00::/32",
+    "2606:4700::/32",
+    "2803:f800::/32",
+    "2405:b500::/32",
+    "2405:8100::/32",
+    "2a06:98c0::/29",
+    "2c0f:f248::/32",
+];
+
+// names, and their subdomains, that are always served by cloudflare
+const CLOUDFLARE_DOMAINS: &[&str] = &[
+    "cloudflare.com",
+    "cloudflare-dns.com",
+    "cloudflareclient.com",
+    "one.one.one.one",
+    "workers.dev",
+    "pages.dev",
+];
+
+// milliseconds a lookup may hold up a connect before it is dialed direct
+const LOOKUP_TIMEOUT: u64 = 500;
+
+static LOADED_RANGES: IsolateCache<CloudflareRanges> = IsolateCache::new();
+
+// workers sockets cannot connect to cloudflare's own addresses, those
+// destinations are only reachable through the proxy ip
+pub struct CloudflareRanges {
+    ranges: Vec<Cidr>,
+}
+
+impl Default for CloudflareRanges {
+    fn default() -> Self {
+        Self {
+            ranges: CLOUDFLARE_RANGES.iter().map(|cidr| cidr.parse().unwrap()).collect(),
+        }
+    }
+}
+
+impl CloudflareRanges {
+    // malformed or unreadable ranges keep the last good ones, or the
+    // published list
+    pub async fn load(env: &Env) -> Arc<Self> {
+        LOADED_RANGES.load("cloudflare", Self::fetch(env)).await
+    }
+
+    async fn fetch(env: &Env) -> Result<Self> {
+        let ranges_str = env.kv("SIREN")?.get("cloudflare_ranges").text().await?.unwrap_or_default();
+        if ranges_str.is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(Self {
+            ranges: serde_json::from_str(&ranges_str)?,
+        })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.ranges.iter().any(|cidr| cidr.contains(&ip))
+    }
+
+    // whether `addr` is, or resolves through doh into, a cloudflare address.
+    // Cached answers return at once, and a lookup that outlasts
+    // LOOKUP_TIMEOUT counts as not cloudflare, so the direct attempt goes
+    // first and the proxy ips remain the fallback.
+    pub async fn hosts(&self, dns: &DnsConfig, addr: &str) -> bool {
+        if let Ok(ip) = addr.parse::<IpAddr>() {
+            return self.contains(&ip);
+        }
+        if is_cloudflare_domain(addr) {
+            return true;
+        }
+
+        let lookup = crate::dns::lookup(dns, addr);
+        let delay = Delay::from(Duration::from_millis(LOOKUP_TIMEOUT));
+        pin_mut!(lookup, delay);
+        match select(lookup, delay).await {
+            Either::Left((Ok(addrs), _)) => addrs.iter().any(|ip| self.contains(ip)),
+            Either::Left((Err(e), _)) => {
+                console_error!("[cloudflare]: resolving {}: {}", addr, e);
+                false
+            }
+            Either::Right(_) => {
+                console_warn!("[cloudflare]: resolving {} timed out, dialing direct", addr);
+                false
+            }
+        }
+    }
+}
+
+fn is_cloudflare_domain(name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    CLOUDFLARE_DOMAINS.iter().any(|domain| {
+        name == *domain || name.strip_suffix(domain).is_some_and(|x| x.ends_with('.'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contains() {
+        let ranges = CloudflareRanges::default();
+        assert!(ranges.contains(&"104.16.132.229".parse().unwrap()));
+        assert!(ranges.contains(&"2606:4700::6810:84e5".parse().unwrap()));
+        assert!(ranges.contains(&"::ffff:172.67.1.1".parse().unwrap()));
+        assert!(!ranges.contains(&"8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_is_cloudflare_domain() {
+        assert!(is_cloudflare_domain("speed.cloudflare.com"));
+        assert!(is_cloudflare_domain("Example.Workers.Dev."));
+        assert!(is_cloudflare_domain("one.one.one.one"));
+        assert!(!is_cloudflare_domain("notcloudflare.com"));
+        assert!(!is_cloudflare_domain("example.com"));
+    }
+}
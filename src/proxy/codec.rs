@@ -0,0 +1,9 @@
+use bytes::BytesMut;
+
+// framing layer applied by `ProxyStream` between the websocket and the
+// proxied connection, e.g. encrypted chunk streams
+pub trait Codec {
+    // decodes every complete frame in `src` into `dst`, leaving partial frames
+    fn decode(&mut self, src: &mut BytesMut, dst: &mut BytesMut) -> std::io::Result<()>;
+    fn encode(&mut self, src: &[u8]) -> std::io::Result<Vec<u8>>;
+}
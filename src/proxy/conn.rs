@@ -1,9 +1,14 @@
-use crate::config::Config;
+use crate::common::unix_timestamp;
+use crate::config::{Config, Protocol, User};
+use super::codec::Codec;
+use super::pool::{self, PROXY_HEALTH};
+use super::route::{parse_host_port, Action, Destination};
 
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use bytes::{BufMut, BytesMut};
-use futures_util::Stream;
+use futures_util::future::{select, Either};
+use futures_util::{pin_mut, Stream};
 use pin_project_lite::pin_project;
 use pretty_bytes::converter::convert;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
@@ -11,12 +16,28 @@ use worker::*;
 
 static MAX_WEBSOCKET_SIZE: usize = 64 * 1024; // 64kb
 static MAX_BUFFER_SIZE: usize = 512 * 1024; // 512kb
+static MAX_REPLAY_SIZE: usize = 64 * 1024; // 64kb
+
+// outcome of one outbound candidate
+enum Attempt {
+    // the remote answered and the session ran on it, with the connect time
+    // in milliseconds and the bytes copied each way
+    Served { latency: u64, up: u64, down: u64 },
+    // connect failed, which counts against a proxy ip's health
+    Unreachable(Error),
+    // the remote closed or failed before answering. Either way the client
+    // data seen so far can be replayed to the next candidate.
+    Retry(Error),
+}
 
 pin_project! {
     pub struct ProxyStream<'a> {
         pub config: Config,
+        pub user: Option<User>,
         pub ws: &'a WebSocket,
         pub buffer: BytesMut,
+        pub codec: Option<Box<dyn Codec>>,
+        pub decoded: BytesMut,
         #[pin]
         pub events: EventStream<'a>,
     }
@@ -28,8 +49,11 @@ impl<'a> ProxyStream<'a> {
 
         Self {
             config,
+            user: None,
             ws,
             buffer,
+            codec: None,
+            decoded: BytesMut::new(),
             events,
         }
     }
@@ -48,7 +72,7 @@ impl<'a> ProxyStream<'a> {
                     break;
                 }
                 Some(Err(e)) => {
-                    return Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()));
+                    return Err(std::io::Error::other(e.to_string()));
                 }
                 None => {
                     break;
@@ -72,15 +96,18 @@ impl<'a> ProxyStream<'a> {
             return Err(Error::RustError("not enough buffer".to_string()));
         }
 
-        if self.is_vless(peeked_buffer) {
+        // vless and trojan are told apart by their header layout, which a
+        // random shadowsocks salt is unlikely to match, and the costlier
+        // trial decryption of shadowsocks only runs when neither does
+        if Self::is_vless(peeked_buffer) {
             console_log!("vless detected!");
             self.process_vless().await
+        } else if Self::is_trojan(peeked_buffer) {
+            console_log!("trojan detected!");
+            self.process_trojan().await
         } else if self.is_shadowsocks(peeked_buffer) {
             console_log!("shadowsocks detected!");
             self.process_shadowsocks().await
-        } else if self.is_trojan(peeked_buffer) {
-            console_log!("trojan detected!");
-            self.process_trojan().await
         } else if self.is_vmess(peeked_buffer) {
             console_log!("vmess detected!");
             self.process_vmess().await
@@ -89,82 +116,324 @@ impl<'a> ProxyStream<'a> {
         }
     }
 
-    pub fn is_vless(&self, buffer: &[u8]) -> bool {
-        buffer[0] == 0
+    // version 0, uuid, addons, then a tcp, udp or mux command, the port and
+    // a known address type
+    pub fn is_vless(buffer: &[u8]) -> bool {
+        if buffer.len() < 18 || buffer[0] != 0 {
+            return false;
+        }
+        let command = 18 + buffer[17] as usize;
+        match buffer.get(command..command + 4) {
+            Some(header) => (1..=3).contains(&header[0]) && (1..=4).contains(&header[3]),
+            None => false,
+        }
+    }
+
+    // hex sha224 of the password, crlf, then a connect or udp associate
+    // command and a socks address type
+    fn is_trojan(buffer: &[u8]) -> bool {
+        buffer.len() > 59
+            && buffer[..56].iter().all(u8::is_ascii_hexdigit)
+            && buffer[56] == 13
+            && buffer[57] == 10
+            && matches!(buffer[58], 1 | 3)
+            && matches!(buffer[59], 1 | 3 | 4)
     }
 
-    fn is_shadowsocks(&self, buffer: &[u8]) -> bool {
-        match buffer[0] {
-            1 => { // IPv4
-                if buffer.len() < 7 {
-                    return false;
-                }
-                let remote_port = u16::from_be_bytes([buffer[5], buffer[6]]);
-                remote_port != 0
-            }
-            3 => { // Domain name
-                if buffer.len() < 2 {
-                    return false;
-                }
-                let domain_len = buffer[1] as usize;
-                if buffer.len() < 2 + domain_len + 2 {
-                    return false;
+    fn is_vmess(&self, buffer: &[u8]) -> bool {
+        !buffer.is_empty() // fallback
+    }
+
+    // routes a tcp connect request and tries the resulting candidates in order
+    pub async fn handle_tcp(&mut self, protocol: Protocol, addr: String, port: u16) -> Result<()> {
+        if port == 53 {
+            return self.handle_dns_outbound().await;
+        }
+
+        if !self.config.policy.permits(&addr) {
+            let user = self.user.as_ref().map(|user| user.id.to_string()).unwrap_or_default();
+            console_warn!("[policy]: blocked {} connect by user {} to {}:{}", protocol, user, addr, port);
+            return Err(Error::RustError(format!("{}:{} is not a permitted destination", addr, port)));
+        }
+
+        let dest = Destination {
+            addr: &addr,
+            port,
+            protocol,
+            user: self.user.as_ref(),
+        };
+        // candidates with whether their health is tracked
+        let direct = (addr.clone(), port, false);
+        let proxies = self.proxy_candidates(&self.config.proxies);
+        let action = self.config.routes.route(&dest).cloned();
+        let addr_pool = match action {
+            Some(Action::Direct) => vec![direct],
+            Some(Action::Proxy) => proxies,
+            Some(Action::Group(name)) => {
+                let entries = self
+                    .config
+                    .routes
+                    .group(&name)
+                    .ok_or_else(|| Error::RustError(format!("unknown proxy group: {}", name)))?;
+                let group = entries
+                    .iter()
+                    .map(|entry| {
+                        parse_host_port(entry)
+                            .ok_or_else(|| Error::RustError(format!("invalid proxy group entry: {}", entry)))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if group.is_empty() {
+                    return Err(Error::RustError(format!("empty proxy group: {}", name)));
                 }
-                let remote_port = u16::from_be_bytes([
-                    buffer[2 + domain_len],
-                    buffer[2 + domain_len + 1],
-                ]);
-                remote_port != 0
+                let group = self.config.strategy.order(&name, self.config.client_ip.as_deref(), group);
+                self.proxy_candidates(&group)
             }
-            4 => { // IPv6
-                if buffer.len() < 19 {
-                    return false;
-                }
-                let remote_port = u16::from_be_bytes([buffer[17], buffer[18]]);
-                remote_port != 0
+            Some(Action::Block) => {
+                return Err(Error::RustError(format!("{}:{} blocked by routing rule", addr, port)));
             }
-            _ => false,
+            // a direct attempt at a cloudflare address is bound to fail
+            None if self.config.cloudflare.hosts(&self.config.dns, &addr).await => proxies,
+            None => [vec![direct], proxies].concat(),
+        };
+
+        let mut outbound = SocketOutbound {
+            health_kv: self.config.health_kv.clone(),
+        };
+        relay_candidates(self, &mut outbound, addr_pool).await
+    }
+
+    fn proxy_candidates(&self, proxies: &[(String, u16)]) -> Vec<(String, u16, bool)> {
+        PROXY_HEALTH
+            .lock()
+            .unwrap()
+            .candidates(proxies, unix_timestamp())
+            .into_iter()
+            .map(|(addr, port)| (addr, port, true))
+            .collect()
+    }
+
+    pub async fn handle_udp_outbound(&mut self, port: u16) -> Result<()> {
+        if port != 53 {
+            return Err(Error::RustError(format!("udp is only supported for dns, not port {}", port)));
         }
+
+        self.handle_dns_outbound().await
     }
 
-    fn is_trojan(&self, buffer: &[u8]) -> bool {
-        buffer.len() > 57 && buffer[56] == 13 && buffer[57] == 10
+    // answers dns messages over doh until the client closes
+    pub async fn handle_dns_outbound(&mut self) -> Result<()> {
+        let dns = &self.config.dns.clone();
+        serve_dns(self, |query| async move { crate::dns::doh(dns, &query).await }).await
     }
+}
 
-    fn is_vmess(&self, buffer: &[u8]) -> bool {
-        buffer.len() > 0 // fallback
-    }
-
-    pub async fn handle_tcp_outbound(&mut self, addr: String, port: u16) -> Result<()> {
-        let mut remote_socket = Socket::builder().connect(&addr, port).map_err(|e| {
-            Error::RustError(e.to_string())
-        })?;
-
-        remote_socket.opened().await.map_err(|e| {
-            Error::RustError(e.to_string())
-        })?;
-
-        tokio::io::copy_bidirectional(self, &mut remote_socket)
-            .await
-            .map(|(a_to_b, b_to_a)| {
-                console_log!("copied data from {}:{}, up: {} and dl: {}", &addr, &port, convert(a_to_b as f64), convert(b_to_a as f64));
-            })
-            .map_err(|e| {
-                Error::RustError(e.to_string())
-            })?;
-        Ok(())
+// answers dns messages with `resolve` until the client closes, framed the
+// same way for vless udp and dns over tcp (rfc 1035 section 4.2.2)
+//
+// +-------------------+-------------------+
+// |      Length       |      Payload      |
+// +-------------------+-------------------+
+// |  2 Bytes (u16be)  |      N Bytes      |
+// +-------------------+-------------------+
+async fn serve_dns<S, F, Fut>(stream: &mut S, mut resolve: F) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut(Vec<u8>) -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<u8>>>,
+{
+    loop {
+        let len = match stream.read_u16().await {
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        let mut query = vec![0u8; len as _];
+        stream.read_exact(&mut query).await?;
+
+        let answer = resolve(query).await?;
+        let len = u16::try_from(answer.len())
+            .map_err(|_| Error::RustError(format!("dns answer of {} bytes does not fit a frame", answer.len())))?;
+        let mut frame = Vec::with_capacity(2 + answer.len());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(&answer);
+        stream.write_all(&frame).await?;
     }
 
-    pub async fn handle_udp_outbound(&mut self) -> Result<()> {
-        let mut buff = vec![0u8; 65535];
+    Ok(())
+}
+
+// dials outbound candidates and sees how each attempt went, so the fallback
+// in `relay_candidates` runs the same over sockets and in tests
+trait Outbound {
+    type Stream: AsyncRead + AsyncWrite + Unpin;
+
+    // the connected stream and the connect time in milliseconds
+    async fn connect(&mut self, addr: &str, port: u16) -> Result<(Self::Stream, u64)>;
+
+    async fn attempted(&mut self, addr: &str, port: u16, tracked: bool, attempt: &Attempt);
+}
+
+struct SocketOutbound {
+    health_kv: Option<kv::KvStore>,
+}
+
+impl Outbound for SocketOutbound {
+    type Stream = Socket;
+
+    async fn connect(&mut self, addr: &str, port: u16) -> Result<(Socket, u64)> {
+        let started = Date::now().as_millis();
+        let socket = Socket::builder().connect(addr, port)?;
+        socket.opened().await?;
+        Ok((socket, Date::now().as_millis().saturating_sub(started)))
+    }
 
-        let n = self.read(&mut buff).await?;
-        let data = &buff[..n];
-        if crate::dns::doh(data).await.is_ok() {
-            self.write(&data).await?;
+    async fn attempted(&mut self, addr: &str, port: u16, tracked: bool, attempt: &Attempt) {
+        match attempt {
+            Attempt::Served { up, down, .. } => console_log!(
+                "copied data from {}:{}, up: {} and dl: {}",
+                addr,
+                port,
+                convert(*up as f64),
+                convert(*down as f64)
+            ),
+            Attempt::Unreachable(e) | Attempt::Retry(e) => {
+                console_warn!("[tcp]: {}:{} unusable: {}", addr, port, e)
+            }
+        }
+        if !tracked {
+            return;
+        }
+
+        match attempt {
+            Attempt::Served { latency, .. } => {
+                PROXY_HEALTH.lock().unwrap().record_success(addr, port, *latency, unix_timestamp());
+            }
+            Attempt::Unreachable(_) => {
+                if PROXY_HEALTH.lock().unwrap().record_failure(addr, port, unix_timestamp()) {
+                    console_warn!("[pool]: {}:{} keeps failing, cooling down", addr, port);
+                }
+            }
+            // the proxy ip itself was reachable
+            Attempt::Retry(_) => return,
+        }
+
+        if let Some(kv) = self.health_kv.as_ref() {
+            if let Err(e) = pool::persist(kv).await {
+                console_error!("[pool]: persisting proxy health: {}", e);
+            }
+        }
+    }
+}
+
+// tries the candidates in order until one serves the session. Client data
+// sent before the first response byte is replayed to the next candidate when
+// a connect fails or the remote closes silently.
+async fn relay_candidates<C, O>(client: &mut C, outbound: &mut O, candidates: Vec<(String, u16, bool)>) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    O: Outbound,
+{
+    let mut replay = Some(Vec::new());
+    let mut last_error = None;
+    for (addr, port, tracked) in candidates {
+        let attempt = match outbound.connect(&addr, port).await {
+            Ok((mut remote, latency)) => relay(client, &mut remote, latency, &mut replay).await?,
+            Err(e) => Attempt::Unreachable(e),
         };
-        Ok(())
+        outbound.attempted(&addr, port, tracked, &attempt).await;
+        match attempt {
+            Attempt::Served { .. } => return Ok(()),
+            Attempt::Unreachable(e) | Attempt::Retry(e) => {
+                if replay.is_none() {
+                    return Err(Error::RustError("client data exceeds the replay buffer, not falling back".to_string()));
+                }
+                last_error = Some(e);
+            }
+        }
     }
+
+    Err(last_error.unwrap_or_else(|| Error::RustError("no outbound candidate".to_string())))
+}
+
+// relays until the remote answers, keeping client data in `replay`, then
+// commits to the remote for the rest of the session
+async fn relay<C, R>(client: &mut C, remote: &mut R, latency: u64, replay: &mut Option<Vec<u8>>) -> Result<Attempt>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + AsyncWrite + Unpin,
+{
+    let retry = |e: std::io::Error| Attempt::Retry(Error::RustError(e.to_string()));
+
+    if let Some(replay) = replay.as_ref().filter(|replay| !replay.is_empty()) {
+        if let Err(e) = remote.write_all(replay).await {
+            return Ok(retry(e));
+        }
+    }
+
+    let mut up = vec![0u8; MAX_WEBSOCKET_SIZE];
+    let mut down = vec![0u8; MAX_WEBSOCKET_SIZE];
+    let mut uploaded = replay.as_ref().map_or(0, |replay| replay.len()) as u64;
+    let mut client_eof = false;
+    let first = loop {
+        let event = {
+            let remote_read = remote.read(&mut down);
+            if client_eof {
+                Either::Right(remote_read.await)
+            } else {
+                let client_read = client.read(&mut up);
+                pin_mut!(client_read, remote_read);
+                match select(client_read, remote_read).await {
+                    Either::Left((n, _)) => Either::Left(n),
+                    Either::Right((n, _)) => Either::Right(n),
+                }
+            }
+        };
+
+        match event {
+            Either::Left(n) => {
+                let n = n?;
+                if n == 0 {
+                    client_eof = true;
+                    if let Err(e) = remote.shutdown().await {
+                        return Ok(retry(e));
+                    }
+                    continue;
+                }
+
+                uploaded += n as u64;
+                if let Some(buf) = replay {
+                    if buf.len() + n > MAX_REPLAY_SIZE {
+                        *replay = None;
+                    } else {
+                        buf.extend_from_slice(&up[..n]);
+                    }
+                }
+                if let Err(e) = remote.write_all(&up[..n]).await {
+                    return Ok(retry(e));
+                }
+            }
+            Either::Right(Ok(0)) => {
+                return Ok(Attempt::Retry(Error::RustError("closed without a response".to_string())));
+            }
+            Either::Right(Ok(n)) => break n,
+            Either::Right(Err(e)) => return Ok(retry(e)),
+        }
+    };
+
+    // the remote answered, nothing is replayed from here on
+    *replay = None;
+    client.write_all(&down[..first]).await?;
+
+    let (a_to_b, b_to_a) = if client_eof {
+        (0, tokio::io::copy(remote, client).await?)
+    } else {
+        tokio::io::copy_bidirectional(client, remote).await?
+    };
+    Ok(Attempt::Served {
+        latency,
+        up: uploaded + a_to_b,
+        down: first as u64 + b_to_a,
+    })
 }
 
 impl<'a> AsyncRead for ProxyStream<'a> {
@@ -176,9 +445,16 @@ impl<'a> AsyncRead for ProxyStream<'a> {
         let mut this = self.project();
 
         loop {
-            let size = std::cmp::min(this.buffer.len(), buf.remaining());
+            let ready = match this.codec.as_mut() {
+                Some(codec) => {
+                    codec.decode(this.buffer, this.decoded)?;
+                    &mut *this.decoded
+                }
+                None => &mut *this.buffer,
+            };
+            let size = std::cmp::min(ready.len(), buf.remaining());
             if size > 0 {
-                buf.put_slice(&this.buffer.split_to(size));
+                buf.put_slice(&ready.split_to(size));
                 return Poll::Ready(Ok(()));
             }
 
@@ -186,7 +462,7 @@ impl<'a> AsyncRead for ProxyStream<'a> {
                 Poll::Ready(Some(Ok(WebsocketEvent::Message(msg)))) => {
                     if let Some(data) = msg.bytes() {
                         if data.len() > MAX_WEBSOCKET_SIZE {
-                            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::Other, "websocket buffer too long")))
+                            return Poll::Ready(Err(std::io::Error::other("websocket buffer too long")))
                         }
                         
                         if this.buffer.len() + data.len() > MAX_BUFFER_SIZE {
@@ -210,12 +486,16 @@ impl<'a> AsyncWrite for ProxyStream<'a> {
         _: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<tokio::io::Result<usize>> {
-        return Poll::Ready(
-            self.ws
-                .send_with_bytes(buf)
-                .map(|_| buf.len())
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
-        );
+        let this = self.project();
+        let sent = match this.codec.as_mut() {
+            Some(codec) => this.ws.send_with_bytes(codec.encode(buf)?),
+            None => this.ws.send_with_bytes(buf),
+        };
+
+        Poll::Ready(
+            sent.map(|_| buf.len())
+                .map_err(|e| std::io::Error::other(e.to_string())),
+        )
     }
 
     fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
@@ -225,10 +505,183 @@ impl<'a> AsyncWrite for ProxyStream<'a> {
     fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
         match self.ws.close(Some(1000), Some("shutdown".to_string())) {
             Ok(_) => Poll::Ready(Ok(())),
-            Err(e) => Poll::Ready(Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                e.to_string(),
-            ))),
+            Err(e) => Poll::Ready(Err(std::io::Error::other(e.to_string()))),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_protocol() {
+        // unknown credentials still dispatch, so the protocol handler can
+        // reject them with its own error
+        let mut vless = vec![0u8];
+        vless.extend_from_slice(&[0xab; 16]);
+        vless.extend_from_slice(&[0, 1, 0x01, 0xbb, 2, 11]);
+        vless.extend_from_slice(b"example.com");
+        assert!(ProxyStream::is_vless(&vless));
+        assert!(!ProxyStream::is_trojan(&vless));
+
+        let mut trojan = "f".repeat(56).into_bytes();
+        trojan.extend_from_slice(b"\r\n");
+        trojan.extend_from_slice(&[1, 1, 1, 1, 1, 1, 0x01, 0xbb]);
+        trojan.extend_from_slice(b"\r\n");
+        assert!(ProxyStream::is_trojan(&trojan));
+        assert!(!ProxyStream::is_vless(&trojan));
+
+        // a salt that happens to start with 0 but has no valid command
+        let mut salt = vec![0u8; 62];
+        salt[18] = 0x7f;
+        assert!(!ProxyStream::is_vless(&salt));
+        assert!(!ProxyStream::is_vless(&[0u8; 17]));
+
+        trojan[3] = b'z';
+        assert!(!ProxyStream::is_trojan(&trojan));
+    }
+
+    // how a scripted remote behaves once it has read `n` bytes
+    enum Remote {
+        Silent(usize),
+        Answer(usize, &'static [u8]),
+    }
+
+    #[derive(Default)]
+    struct TestOutbound {
+        remotes: std::collections::VecDeque<Remote>,
+        connects: Vec<String>,
+        received: std::sync::Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Outbound for TestOutbound {
+        type Stream = tokio::io::DuplexStream;
+
+        async fn connect(&mut self, addr: &str, port: u16) -> Result<(Self::Stream, u64)> {
+            self.connects.push(format!("{}:{}", addr, port));
+            let remote = self
+                .remotes
+                .pop_front()
+                .ok_or_else(|| Error::RustError("connection refused".to_string()))?;
+            let (stream, mut server) = tokio::io::duplex(1 << 20);
+            let received = self.received.clone();
+            tokio::spawn(async move {
+                let n = match remote {
+                    Remote::Silent(n) | Remote::Answer(n, _) => n,
+                };
+                let mut buf = vec![0u8; n];
+                server.read_exact(&mut buf).await.unwrap();
+                received.lock().unwrap().push(buf);
+                if let Remote::Answer(_, answer) = remote {
+                    server.write_all(answer).await.unwrap();
+                }
+            });
+            Ok((stream, 1))
+        }
+
+        async fn attempted(&mut self, _: &str, _: u16, _: bool, _: &Attempt) {}
+    }
+
+    fn candidates(n: usize) -> Vec<(String, u16, bool)> {
+        (0..n).map(|i| (format!("10.0.0.{}", i), 443, true)).collect()
+    }
+
+    fn run<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(f)
+    }
+
+    #[test]
+    fn test_replay_after_empty_close() {
+        let mut outbound = TestOutbound {
+            remotes: [Remote::Silent(5), Remote::Answer(5, b"world")].into(),
+            ..Default::default()
+        };
+        let (mut client, mut client_end) = tokio::io::duplex(1 << 20);
+        let result = run(async {
+            let session = async {
+                client_end.write_all(b"hello").await.unwrap();
+                let mut answer = [0u8; 5];
+                client_end.read_exact(&mut answer).await.unwrap();
+                drop(client_end);
+                answer
+            };
+            let relay = relay_candidates(&mut client, &mut outbound, candidates(2));
+            futures_util::future::join(session, relay).await
+        });
+
+        assert_eq!(&result.0, b"world");
+        assert!(result.1.is_ok());
+        assert_eq!(outbound.connects, ["10.0.0.0:443", "10.0.0.1:443"]);
+        assert_eq!(*outbound.received.lock().unwrap(), [b"hello".to_vec(), b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn test_replay_overflow() {
+        let mut outbound = TestOutbound {
+            remotes: [Remote::Silent(MAX_REPLAY_SIZE + 1), Remote::Answer(0, b"world")].into(),
+            ..Default::default()
+        };
+        let (mut client, mut client_end) = tokio::io::duplex(1 << 20);
+        let result = run(async {
+            client_end.write_all(&vec![7u8; MAX_REPLAY_SIZE + 1]).await.unwrap();
+            relay_candidates(&mut client, &mut outbound, candidates(2)).await
+        });
+
+        assert!(result.is_err());
+        assert_eq!(outbound.connects, ["10.0.0.0:443"]);
+    }
+
+    #[test]
+    fn test_no_fallback_after_served() {
+        let mut outbound = TestOutbound {
+            remotes: [Remote::Answer(5, b"world"), Remote::Answer(5, b"world")].into(),
+            ..Default::default()
+        };
+        let (mut client, mut client_end) = tokio::io::duplex(1 << 20);
+        let result = run(async {
+            let session = async {
+                client_end.write_all(b"hello").await.unwrap();
+                client_end.shutdown().await.unwrap();
+                let mut answer = [0u8; 5];
+                client_end.read_exact(&mut answer).await.unwrap();
+                answer
+            };
+            let relay = relay_candidates(&mut client, &mut outbound, candidates(2));
+            futures_util::future::join(session, relay).await
+        });
+
+        assert_eq!(&result.0, b"world");
+        assert!(result.1.is_ok());
+        assert_eq!(outbound.connects, ["10.0.0.0:443"]);
+    }
+
+
+    #[test]
+    fn test_serve_dns() {
+        let (mut stream, mut client) = tokio::io::duplex(1 << 16);
+        let answers = run(async {
+            // two queries in one write, then the client closes
+            client.write_all(b"\x00\x03one\x00\x05three").await.unwrap();
+            client.shutdown().await.unwrap();
+            serve_dns(&mut stream, |query| async move { Ok([&query[..], b"!"].concat()) })
+                .await
+                .unwrap();
+            drop(stream);
+
+            let mut answers = Vec::new();
+            client.read_to_end(&mut answers).await.unwrap();
+            answers
+        });
+        assert_eq!(answers, b"\x00\x04one!\x00\x06three!");
+
+        // a frame cut short is an error, unlike a close between frames
+        let (mut stream, mut client) = tokio::io::duplex(1 << 16);
+        let result = run(async {
+            client.write_all(b"\x00\x05thr").await.unwrap();
+            client.shutdown().await.unwrap();
+            serve_dns(&mut stream, |query| async move { Ok(query) }).await
+        });
+        assert!(result.is_err());
+    }
+}
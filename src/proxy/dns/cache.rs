@@ -0,0 +1,160 @@
+use super::message::{self, Question, RCODE_NOERROR, RCODE_NXDOMAIN};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+
+const MAX_ENTRIES: usize = 2048;
+
+pub static DNS_CACHE: Lazy<Mutex<DnsCache>> = Lazy::new(|| Mutex::new(DnsCache::new(MAX_ENTRIES)));
+
+struct Entry {
+    answer: Vec<u8>,
+    inserted: u64,
+    expires: u64,
+    // end of the question section in `answer`
+    question_end: usize,
+    ttl_offsets: Vec<(usize, u32)>,
+}
+
+// isolate-local answer cache keyed by question, expiring with the smallest
+// ttl in the answer
+pub struct DnsCache {
+    capacity: usize,
+    entries: HashMap<Question, Entry>,
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+        }
+    }
+
+    // returns a cached answer with the query's id and question, so the name
+    // keeps the query's letter case, and ttls reduced by the time spent in
+    // cache
+    pub fn get(&mut self, query: &[u8], now: u64) -> Option<Vec<u8>> {
+        let (question, question_end) = message::parse_question(query).ok()?;
+        let entry = self.entries.get(&question)?;
+        if now >= entry.expires {
+            self.entries.remove(&question);
+            return None;
+        }
+        // a differently encoded name cannot be copied over in place
+        if entry.question_end != question_end {
+            return None;
+        }
+
+        let elapsed = (now - entry.inserted) as u32;
+        let mut answer = entry.answer.clone();
+        answer[..2].copy_from_slice(&query[..2]);
+        answer[12..question_end].copy_from_slice(&query[12..question_end]);
+        for (offset, ttl) in entry.ttl_offsets.iter() {
+            answer[*offset..*offset + 4].copy_from_slice(&ttl.saturating_sub(elapsed).to_be_bytes());
+        }
+        Some(answer)
+    }
+
+    pub fn put(&mut self, query: &[u8], answer: &[u8], now: u64) {
+        let Ok((question, _)) = message::parse_question(query) else {
+            return;
+        };
+        if !matches!(message::rcode(answer), Ok(RCODE_NOERROR | RCODE_NXDOMAIN))
+            || message::is_truncated(answer).unwrap_or(true)
+        {
+            return;
+        }
+        let Ok((_, question_end)) = message::parse_question(answer) else {
+            return;
+        };
+        let Ok(ttl_offsets) = message::ttl_offsets(answer) else {
+            return;
+        };
+        let Some(ttl) = ttl_offsets.iter().map(|(_, ttl)| *ttl).min() else {
+            return;
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.entries.retain(|_, entry| entry.expires > now);
+        }
+        if self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(question, _)| question.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(
+            question,
+            Entry {
+                answer: answer.to_vec(),
+                inserted: now,
+                expires: now + ttl as u64,
+                question_end,
+                ttl_offsets,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(id: u16) -> Vec<u8> {
+        query_name(id, b"Example")
+    }
+
+    fn query_name(id: u16, name: &[u8]) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        query.push(name.len() as u8);
+        query.extend_from_slice(name);
+        query.extend_from_slice(b"\x03com\x00\x00\x01\x00\x01");
+        query
+    }
+
+    fn answer(id: u16, ttl: u32) -> Vec<u8> {
+        answer_name(id, b"Example", ttl)
+    }
+
+    fn answer_name(id: u16, name: &[u8], ttl: u32) -> Vec<u8> {
+        let mut answer = query_name(id, name);
+        answer[2] = 0x81;
+        answer[3] = 0x80;
+        answer[7] = 1;
+        // compressed name pointing at the question, A IN, ttl, 1.2.3.4
+        answer.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        answer.extend_from_slice(&ttl.to_be_bytes());
+        answer.extend_from_slice(&[0, 4, 1, 2, 3, 4]);
+        answer
+    }
+
+    #[test]
+    fn test_cache_hit_rewrites_id_and_ttl() {
+        let mut cache = DnsCache::new(16);
+        cache.put(&query(1), &answer(1, 300), 1000);
+
+        let hit = cache.get(&query(2), 1100).unwrap();
+        assert_eq!(hit, answer(2, 200));
+        assert!(cache.get(&query(3), 1300).is_none());
+    }
+
+    #[test]
+    fn test_cache_hit_keeps_query_case() {
+        let mut cache = DnsCache::new(16);
+        cache.put(&query_name(1, b"example"), &answer_name(1, b"example", 300), 1000);
+
+        let hit = cache.get(&query_name(2, b"eXaMpLe"), 1000).unwrap();
+        assert_eq!(hit, answer_name(2, b"eXaMpLe", 300));
+    }
+}
@@ -0,0 +1,137 @@
+use super::message::{self, RCODE_NOERROR, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA};
+use crate::common::cache::IsolateCache;
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use serde::Deserialize;
+use worker::*;
+
+// ttl of locally answered records
+const LOCAL_TTL: u32 = 60;
+
+static DNS_FILTER: IsolateCache<DnsFilter> = IsolateCache::new();
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockMode {
+    #[default]
+    Nxdomain,
+    // 0.0.0.0 and :: answers
+    Zero,
+}
+
+// `dns_filter` key in SIREN kv:
+// { "block_mode": "nxdomain", "blocklist": ["ads.example.com"], "hosts": { "nas.lan": ["10.0.0.2"] } }
+#[derive(Default, Deserialize)]
+pub struct DnsFilter {
+    #[serde(default)]
+    pub block_mode: BlockMode,
+    // blocking a domain also blocks its subdomains
+    #[serde(default)]
+    blocklist: HashSet<String>,
+    #[serde(default)]
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl DnsFilter {
+    // a malformed or unreadable filter keeps the last good one, or none
+    pub async fn load(env: &Env) -> Arc<Self> {
+        DNS_FILTER.load("dns filter", Self::fetch(env)).await
+    }
+
+    async fn fetch(env: &Env) -> Result<Self> {
+        let filter_str = env.kv("SIREN")?.get("dns_filter").text().await?.unwrap_or_default();
+        if filter_str.is_empty() {
+            return Ok(Self::default());
+        }
+        Self::parse(&filter_str)
+    }
+
+    fn parse(filter_str: &str) -> Result<Self> {
+        let mut filter: Self = serde_json::from_str(filter_str)?;
+        filter.blocklist = filter
+            .blocklist
+            .iter()
+            .map(|domain| domain.trim_end_matches('.').to_ascii_lowercase())
+            .collect();
+        filter.hosts = filter
+            .hosts
+            .into_iter()
+            .map(|(name, ips)| (name.trim_end_matches('.').to_ascii_lowercase(), ips))
+            .collect();
+        Ok(filter)
+    }
+
+    fn is_blocked(&self, name: &str) -> bool {
+        let mut suffix = name;
+        loop {
+            if self.blocklist.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => return false,
+            }
+        }
+    }
+
+    // answers `query` locally when its name is blocked or statically mapped
+    pub fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let (question, _) = message::parse_question(query).ok()?;
+
+        let records: Vec<(u16, Vec<u8>)> = if let Some(ips) = self.hosts.get(&question.name) {
+            ips.iter()
+                .filter_map(|ip| match ip {
+                    IpAddr::V4(ip) if question.qtype == TYPE_A => Some((TYPE_A, ip.octets().to_vec())),
+                    IpAddr::V6(ip) if question.qtype == TYPE_AAAA => Some((TYPE_AAAA, ip.octets().to_vec())),
+                    _ => None,
+                })
+                .collect()
+        } else if self.is_blocked(&question.name) {
+            if self.block_mode == BlockMode::Nxdomain {
+                return message::build_response(query, RCODE_NXDOMAIN, &[], LOCAL_TTL).ok();
+            }
+            match question.qtype {
+                TYPE_A => vec![(TYPE_A, vec![0u8; 4])],
+                TYPE_AAAA => vec![(TYPE_AAAA, vec![0u8; 16])],
+                _ => Vec::new(),
+            }
+        } else {
+            return None;
+        };
+
+        message::build_response(query, RCODE_NOERROR, &records, LOCAL_TTL).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &[u8], qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(name);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&[0, 1]);
+        query
+    }
+
+    #[test]
+    fn test_answer() {
+        let filter = DnsFilter::parse(
+            r#"{"block_mode": "zero", "blocklist": ["Ads.example."], "hosts": {"nas.lan": ["10.0.0.2"]}}"#,
+        )
+        .unwrap();
+
+        let blocked = filter.answer(&query(b"\x03cdn\x03ads\x07example\x00", TYPE_A)).unwrap();
+        assert_eq!(message::rcode(&blocked).unwrap(), RCODE_NOERROR);
+        assert_eq!(&blocked[blocked.len() - 4..], &[0, 0, 0, 0]);
+
+        let host = filter.answer(&query(b"\x03nas\x03lan\x00", TYPE_A)).unwrap();
+        assert_eq!(&host[host.len() - 4..], &[10, 0, 0, 2]);
+        assert_eq!(message::ttl_offsets(&host).unwrap().len(), 1);
+
+        assert!(filter.answer(&query(b"\x07example\x00", TYPE_A)).is_none());
+    }
+}
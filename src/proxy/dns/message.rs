@@ -0,0 +1,195 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use worker::*;
+
+// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1
+//
+// +---------------------+
+// |        Header       |
+// +---------------------+
+// |       Question      | the question for the name server
+// +---------------------+
+// |        Answer       | RRs answering the question
+// +---------------------+
+// |      Authority      | RRs pointing toward an authority
+// +---------------------+
+// |      Additional     | RRs holding additional information
+// +---------------------+
+pub const HEADER_LEN: usize = 12;
+pub const CLASS_IN: u16 = 1;
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_OPT: u16 = 41;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Question {
+    // lowercase, dot separated, without the trailing dot
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+fn truncated() -> Error {
+    Error::RustError("truncated dns message".to_string())
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Result<u16> {
+    let b = msg.get(pos..pos + 2).ok_or_else(truncated)?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+}
+
+pub fn rcode(msg: &[u8]) -> Result<u8> {
+    msg.get(3).map(|b| b & 0x0f).ok_or_else(truncated)
+}
+
+pub fn is_truncated(msg: &[u8]) -> Result<bool> {
+    msg.get(2).map(|b| b & 0x02 != 0).ok_or_else(truncated)
+}
+
+// returns the name starting at `pos` and the offset right after it
+pub fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // bounds pointer loops
+    let mut jumps = 0;
+
+    loop {
+        let len = *msg.get(pos).ok_or_else(truncated)? as usize;
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+
+        if len & 0xc0 == 0xc0 {
+            let pointer = read_u16(msg, pos)? as usize & 0x3fff;
+            end.get_or_insert(pos + 2);
+            jumps += 1;
+            if jumps > 64 {
+                return Err(Error::RustError("dns name pointer loop".to_string()));
+            }
+            pos = pointer;
+            continue;
+        }
+
+        let label = msg.get(pos + 1..pos + 1 + len).ok_or_else(truncated)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += 1 + len;
+    }
+
+    Ok((labels.join("."), end.unwrap_or(pos)))
+}
+
+// returns the first question and the offset of the section that follows
+// the question section
+pub fn parse_question(msg: &[u8]) -> Result<(Question, usize)> {
+    let qdcount = read_u16(msg, 4)?;
+    if qdcount == 0 {
+        return Err(Error::RustError("dns message without question".to_string()));
+    }
+
+    let mut question = None;
+    let mut pos = HEADER_LEN;
+    for _ in 0..qdcount {
+        let (name, end) = read_name(msg, pos)?;
+        let qtype = read_u16(msg, end)?;
+        let qclass = read_u16(msg, end + 2)?;
+        question.get_or_insert(Question { name, qtype, qclass });
+        pos = end + 4;
+    }
+
+    Ok((question.ok_or_else(truncated)?, pos))
+}
+
+// offsets and values of every record ttl, skipping EDNS OPT pseudo records
+pub fn ttl_offsets(msg: &[u8]) -> Result<Vec<(usize, u32)>> {
+    let (_, mut pos) = parse_question(msg)?;
+    let records = read_u16(msg, 6)? as usize + read_u16(msg, 8)? as usize + read_u16(msg, 10)? as usize;
+
+    let mut offsets = Vec::with_capacity(records);
+    for _ in 0..records {
+        let (_, end) = read_name(msg, pos)?;
+        let rtype = read_u16(msg, end)?;
+        let ttl = msg.get(end + 4..end + 8).ok_or_else(truncated)?;
+        let rdlength = read_u16(msg, end + 8)? as usize;
+        if rtype != TYPE_OPT {
+            offsets.push((end + 4, u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]])));
+        }
+        pos = end + 10 + rdlength;
+    }
+    if pos > msg.len() {
+        return Err(truncated());
+    }
+
+    Ok(offsets)
+}
+
+// builds a recursive query for `name`, id 0 as recommended for doh
+pub fn build_query(name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut query = vec![0, 0, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::RustError(format!("invalid dns name: {}", name)));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+// addresses carried by the A and AAAA records of the answer section
+pub fn answer_addrs(msg: &[u8]) -> Result<Vec<IpAddr>> {
+    let (_, mut pos) = parse_question(msg)?;
+    let ancount = read_u16(msg, 6)?;
+
+    let mut addrs = Vec::new();
+    for _ in 0..ancount {
+        let (_, end) = read_name(msg, pos)?;
+        let rtype = read_u16(msg, end)?;
+        let rdlength = read_u16(msg, end + 8)? as usize;
+        let rdata = msg.get(end + 10..end + 10 + rdlength).ok_or_else(truncated)?;
+        match (rtype, rdlength) {
+            (TYPE_A, 4) => addrs.push(IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+        pos = end + 10 + rdlength;
+    }
+
+    Ok(addrs)
+}
+
+// answers the first question of `query` with `rcode` and the given records,
+// every answer owning the question name
+pub fn build_response(query: &[u8], rcode: u8, answers: &[(u16, Vec<u8>)], ttl: u32) -> Result<Vec<u8>> {
+    let (_, end) = parse_question(query)?;
+
+    let mut response = query[..end].to_vec();
+    // QR, keep opcode and RD
+    response[2] = 0x80 | (query[2] & 0x79);
+    // RA
+    response[3] = 0x80 | (rcode & 0x0f);
+    response[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+    response[8..HEADER_LEN].fill(0);
+
+    for (rtype, rdata) in answers.iter() {
+        // pointer to the question name
+        response.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        response.extend_from_slice(&rtype.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&ttl.to_be_bytes());
+        response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        response.extend_from_slice(rdata);
+    }
+
+    Ok(response)
+}
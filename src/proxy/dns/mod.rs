@@ -0,0 +1,183 @@
+pub mod cache;
+pub mod filter;
+pub mod message;
+
+use crate::common::unix_timestamp;
+use crate::config::DnsConfig;
+
+use std::net::IpAddr;
+use std::time::Duration;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use futures_util::future::{select, select_ok, Either};
+use futures_util::pin_mut;
+use worker::*;
+
+async fn query_upstream(url: &str, query: &[u8], timeout: u64) -> Result<Vec<u8>> {
+    let mut headers = Headers::new();
+    headers.set("content-type", "application/dns-message")?;
+    headers.set("accept", "application/dns-message")?;
+
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(js_sys::Uint8Array::from(query).into()));
+    let request = Request::new_with_init(url, &init)?;
+
+    let controller = AbortController::default();
+    let signal = controller.signal();
+    let fetch = Fetch::Request(request);
+    let response = fetch.send_with_signal(&signal);
+    let delay = Delay::from(Duration::from_millis(timeout));
+    pin_mut!(response, delay);
+
+    let mut response = match select(response, delay).await {
+        Either::Left((response, _)) => response?,
+        Either::Right(_) => {
+            controller.abort();
+            return Err(Error::RustError(format!("{} timed out after {}ms", url, timeout)));
+        }
+    };
+
+    if response.status_code() != 200 {
+        return Err(Error::RustError(format!("{} responded with {}", url, response.status_code())));
+    }
+    response.bytes().await
+}
+
+async fn resolve(config: &DnsConfig, query: &[u8]) -> Result<Vec<u8>> {
+    if config.upstreams.is_empty() {
+        return Err(Error::RustError("no doh upstream configured".to_string()));
+    }
+
+    if config.race {
+        let queries = config
+            .upstreams
+            .iter()
+            .map(|url| Box::pin(query_upstream(url, query, config.timeout)));
+        return select_ok(queries).await.map(|(answer, _)| answer);
+    }
+
+    let mut last_error = None;
+    for url in config.upstreams.iter() {
+        match query_upstream(url, query, config.timeout).await {
+            Ok(answer) => return Ok(answer),
+            Err(e) => {
+                console_error!("[dns]: {}", e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| Error::RustError("no doh upstream answered".to_string())))
+}
+
+// resolves `query` through the answer cache and the configured upstreams,
+// answering SERVFAIL when all of them fail
+pub async fn doh(config: &DnsConfig, query: &[u8]) -> Result<Vec<u8>> {
+    if let Some(answer) = config.filter.answer(query) {
+        return Ok(answer);
+    }
+
+    let now = unix_timestamp();
+    if let Some(answer) = cache::DNS_CACHE.lock().unwrap().get(query, now) {
+        return Ok(answer);
+    }
+
+    match resolve(config, query).await {
+        Ok(answer) => {
+            cache::DNS_CACHE.lock().unwrap().put(query, &answer, now);
+            Ok(answer)
+        }
+        Err(e) => {
+            console_error!("[dns]: answering servfail: {}", e);
+            servfail(query)
+        }
+    }
+}
+
+// addresses of `name`, A records first and AAAA when there are none
+pub async fn lookup(config: &DnsConfig, name: &str) -> Result<Vec<IpAddr>> {
+    for qtype in [message::TYPE_A, message::TYPE_AAAA] {
+        let answer = doh(config, &message::build_query(name, qtype)?).await?;
+        let addrs = message::answer_addrs(&answer)?;
+        if !addrs.is_empty() {
+            return Ok(addrs);
+        }
+    }
+    Ok(Vec::new())
+}
+
+// echoes the id and question section of `query` with rcode SERVFAIL
+pub fn servfail(query: &[u8]) -> Result<Vec<u8>> {
+    message::build_response(query, message::RCODE_SERVFAIL, &[], 0)
+}
+
+// the query of an rfc 8484 GET request, carried base64url encoded in the
+// `dns` parameter
+pub fn get_query(url: &Url) -> Result<Vec<u8>> {
+    let (_, dns) = url
+        .query_pairs()
+        .find(|(k, _)| k == "dns")
+        .ok_or_else(|| Error::RustError("missing dns parameter".to_string()))?;
+    URL_SAFE_NO_PAD
+        .decode(dns.trim_end_matches('='))
+        .map_err(|_| Error::RustError("invalid dns parameter".to_string()))
+}
+
+// whether a POST body is declared as a dns message
+pub fn is_dns_message(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("application/dns-message"))
+}
+
+// a query fits a dns message length and has a readable question
+pub fn check_query(query: &[u8]) -> Result<()> {
+    if query.len() > u16::MAX as usize {
+        return Err(Error::RustError("dns message too long".to_string()));
+    }
+    message::parse_question(query).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // id 0, RD, one question: example.com A IN
+    const QUERY: &[u8] = b"\x00\x00\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x00\x01";
+
+    #[test]
+    fn test_get_query() {
+        let url = |query: &str| Url::parse(&format!("https://example.com/dns-query?{}", query)).unwrap();
+        let encoded = URL_SAFE_NO_PAD.encode(QUERY);
+
+        assert_eq!(get_query(&url(&format!("dns={}", encoded))).unwrap(), QUERY);
+        // padding is tolerated even though rfc 8484 leaves it out
+        assert_eq!(get_query(&url(&format!("dns={}==", encoded))).unwrap(), QUERY);
+        assert!(get_query(&url("token=abc")).is_err());
+        assert!(get_query(&url("dns=not+base64url")).is_err());
+        assert!(check_query(QUERY).is_ok());
+        assert!(check_query(&QUERY[..20]).is_err());
+    }
+
+    #[test]
+    fn test_is_dns_message() {
+        assert!(is_dns_message(Some("application/dns-message")));
+        assert!(is_dns_message(Some("Application/DNS-Message; charset=binary")));
+        assert!(!is_dns_message(Some("application/json")));
+        assert!(!is_dns_message(None));
+    }
+
+    #[test]
+    fn test_servfail() {
+        // id 0xabcd, RD, one question: example.com A IN, one additional record
+        let mut query = vec![0xab, 0xcd, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1];
+        query.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        let question_end = query.len();
+        query.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+
+        let response = servfail(&query).unwrap();
+        assert_eq!(&response[..4], &[0xab, 0xcd, 0x81, 0x82]);
+        assert_eq!(&response[4..12], &[0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&response[12..], &query[12..question_end]);
+    }
+}
@@ -0,0 +1,199 @@
+use worker::*;
+
+// cloudflare colo (iata code) to the country and continent it serves from
+const COLOS: &[(&str, &str, &str)] = &[
+    // asia
+    ("SIN", "SG", "AS"),
+    ("HKG", "HK", "AS"),
+    ("MFM", "MO", "AS"),
+    ("NRT", "JP", "AS"),
+    ("KIX", "JP", "AS"),
+    ("FUK", "JP", "AS"),
+    ("OKA", "JP", "AS"),
+    ("ICN", "KR", "AS"),
+    ("TPE", "TW", "AS"),
+    ("BKK", "TH", "AS"),
+    ("KUL", "MY", "AS"),
+    ("CGK", "ID", "AS"),
+    ("MNL", "PH", "AS"),
+    ("SGN", "VN", "AS"),
+    ("HAN", "VN", "AS"),
+    ("PNH", "KH", "AS"),
+    ("RGN", "MM", "AS"),
+    ("BOM", "IN", "AS"),
+    ("DEL", "IN", "AS"),
+    ("MAA", "IN", "AS"),
+    ("BLR", "IN", "AS"),
+    ("HYD", "IN", "AS"),
+    ("CCU", "IN", "AS"),
+    ("KHI", "PK", "AS"),
+    ("LHE", "PK", "AS"),
+    ("DAC", "BD", "AS"),
+    ("CMB", "LK", "AS"),
+    ("KTM", "NP", "AS"),
+    ("ULN", "MN", "AS"),
+    ("ALA", "KZ", "AS"),
+    ("TAS", "UZ", "AS"),
+    ("DXB", "AE", "AS"),
+    ("DOH", "QA", "AS"),
+    ("BAH", "BH", "AS"),
+    ("KWI", "KW", "AS"),
+    ("RUH", "SA", "AS"),
+    ("JED", "SA", "AS"),
+    ("AMM", "JO", "AS"),
+    ("TLV", "IL", "AS"),
+    ("IST", "TR", "AS"),
+    // europe
+    ("AMS", "NL", "EU"),
+    ("FRA", "DE", "EU"),
+    ("HAM", "DE", "EU"),
+    ("DUS", "DE", "EU"),
+    ("MUC", "DE", "EU"),
+    ("TXL", "DE", "EU"),
+    ("LHR", "GB", "EU"),
+    ("MAN", "GB", "EU"),
+    ("EDI", "GB", "EU"),
+    ("DUB", "IE", "EU"),
+    ("CDG", "FR", "EU"),
+    ("MRS", "FR", "EU"),
+    ("BRU", "BE", "EU"),
+    ("LUX", "LU", "EU"),
+    ("MAD", "ES", "EU"),
+    ("BCN", "ES", "EU"),
+    ("LIS", "PT", "EU"),
+    ("MXP", "IT", "EU"),
+    ("FCO", "IT", "EU"),
+    ("ZRH", "CH", "EU"),
+    ("GVA", "CH", "EU"),
+    ("VIE", "AT", "EU"),
+    ("PRG", "CZ", "EU"),
+    ("WAW", "PL", "EU"),
+    ("BUD", "HU", "EU"),
+    ("OTP", "RO", "EU"),
+    ("SOF", "BG", "EU"),
+    ("ATH", "GR", "EU"),
+    ("BEG", "RS", "EU"),
+    ("ZAG", "HR", "EU"),
+    ("KBP", "UA", "EU"),
+    ("ARN", "SE", "EU"),
+    ("CPH", "DK", "EU"),
+    ("OSL", "NO", "EU"),
+    ("HEL", "FI", "EU"),
+    ("RIX", "LV", "EU"),
+    ("TLL", "EE", "EU"),
+    ("VNO", "LT", "EU"),
+    // north america
+    ("IAD", "US", "NA"),
+    ("EWR", "US", "NA"),
+    ("BOS", "US", "NA"),
+    ("ATL", "US", "NA"),
+    ("MIA", "US", "NA"),
+    ("ORD", "US", "NA"),
+    ("DFW", "US", "NA"),
+    ("DEN", "US", "NA"),
+    ("PHX", "US", "NA"),
+    ("LAX", "US", "NA"),
+    ("SJC", "US", "NA"),
+    ("SEA", "US", "NA"),
+    ("YYZ", "CA", "NA"),
+    ("YUL", "CA", "NA"),
+    ("YVR", "CA", "NA"),
+    ("MEX", "MX", "NA"),
+    ("QRO", "MX", "NA"),
+    // south america
+    ("GRU", "BR", "SA"),
+    ("GIG", "BR", "SA"),
+    ("EZE", "AR", "SA"),
+    ("SCL", "CL", "SA"),
+    ("BOG", "CO", "SA"),
+    ("LIM", "PE", "SA"),
+    ("UIO", "EC", "SA"),
+    // oceania
+    ("SYD", "AU", "OC"),
+    ("MEL", "AU", "OC"),
+    ("BNE", "AU", "OC"),
+    ("PER", "AU", "OC"),
+    ("AKL", "NZ", "OC"),
+    // africa
+    ("JNB", "ZA", "AF"),
+    ("CPT", "ZA", "AF"),
+    ("LOS", "NG", "AF"),
+    ("NBO", "KE", "AF"),
+    ("CAI", "EG", "AF"),
+    ("CMN", "MA", "AF"),
+];
+
+// where a tunnel request entered cloudflare, from `request.cf`
+#[derive(Debug, Default)]
+pub struct Location {
+    pub colo: Option<String>,
+    pub country: Option<String>,
+    pub continent: Option<String>,
+}
+
+impl Location {
+    pub fn from_request(req: &Request) -> Self {
+        match req.cf() {
+            Some(cf) => Self {
+                colo: Some(cf.colo()),
+                country: cf.country(),
+                continent: cf.continent(),
+            },
+            None => Self::default(),
+        }
+    }
+
+    // the colo's country, then the client's country, then the countries of
+    // the colo's continent, then anywhere. Returns the first of these tiers
+    // with a usable country, as every usable country in it.
+    pub fn nearest(&self, countries: &[String], usable: impl Fn(&str) -> bool) -> Vec<String> {
+        let colo = self
+            .colo
+            .as_deref()
+            .and_then(|colo| COLOS.iter().find(|(code, _, _)| colo.eq_ignore_ascii_case(code)));
+        let continent = colo
+            .map(|(_, _, continent)| continent.to_string())
+            .or_else(|| self.continent.clone());
+        let in_continent = |country: &String| {
+            COLOS.iter().any(|(_, c, region)| c == country && Some(*region) == continent.as_deref())
+        };
+
+        let tiers: [Vec<String>; 4] = [
+            colo.map(|(_, country, _)| vec![country.to_string()]).unwrap_or_default(),
+            self.country.iter().map(|country| country.to_ascii_uppercase()).collect(),
+            countries.iter().filter(|country| in_continent(country)).cloned().collect(),
+            countries.to_vec(),
+        ];
+        tiers
+            .into_iter()
+            .map(|tier| {
+                tier.into_iter()
+                    .filter(|country| countries.contains(country) && usable(country))
+                    .collect::<Vec<_>>()
+            })
+            .find(|tier| !tier.is_empty())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nearest() {
+        let countries: Vec<String> = ["SG", "JP", "DE", "US"].iter().map(|c| c.to_string()).collect();
+        let location = |colo: &str, country: &str| Location {
+            colo: Some(colo.to_string()),
+            country: Some(country.to_string()),
+            continent: None,
+        };
+
+        assert_eq!(location("SIN", "ID").nearest(&countries, |_| true), vec!["SG"]);
+        assert_eq!(location("CGK", "JP").nearest(&countries, |_| true), vec!["JP"]);
+        assert_eq!(location("CGK", "ID").nearest(&countries, |_| true), vec!["SG", "JP"]);
+        assert_eq!(location("CGK", "ID").nearest(&countries, |c| c != "SG"), vec!["JP"]);
+        assert_eq!(location("CPT", "ZA").nearest(&countries, |c| c != "DE"), vec!["SG", "JP", "US"]);
+        assert!(location("SIN", "SG").nearest(&countries, |_| false).is_empty());
+    }
+}
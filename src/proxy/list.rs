@@ -0,0 +1,416 @@
+use super::query::SelectionQuery;
+use super::route::parse_host_port;
+use crate::common::cache::IsolateCache;
+use crate::common::unix_timestamp;
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+const DEFAULT_SOURCE: &str = "https://raw.githubusercontent.com/FoolVPN-ID/Nautica/refs/heads/main/kvProxyList.json";
+// seconds the merged list is cached in kv before the sources are fetched again
+const CACHE_TTL: u64 = 60 * 60 * 24;
+// invalid entries quoted in the log per source
+const MAX_REPORTED: usize = 5;
+
+static PROXY_LIST: IsolateCache<ProxyList> = IsolateCache::new();
+
+// a source entry, optionally naming the isp of the proxy ip
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum Entry {
+    Proxy(String),
+    Detailed { proxy: String, isp: String },
+}
+
+// proxy ips by country code, merged from every source in order and
+// deduplicated. Sources hold
+// { "SG": ["1.2.3.4:443", { "proxy": "5.6.7.8:443", "isp": "Singtel" }], ... }
+#[derive(Default)]
+pub struct ProxyList {
+    countries: HashMap<String, Vec<(String, u16)>>,
+    isps: HashMap<(String, u16), String>,
+    // uppercase alias to country codes, from the `proxy_aliases` key in SIREN
+    // kv: { "ASIA": ["SG", "ID", "JP"], "gaming": ["SG", "HK"] }
+    aliases: HashMap<String, Vec<String>>,
+}
+
+impl ProxyList {
+    // `PROXY_LIST_SOURCES` holds comma separated urls and `kv:<key>` SIREN
+    // kv keys. The merged list is cached under `proxy_kv`, and the last good
+    // one kept under `proxy_kv_backup` for when every source fails.
+    pub async fn load(env: &Env) -> Result<Arc<Self>> {
+        let now = unix_timestamp();
+        if let Some(list) = PROXY_LIST.fresh(now) {
+            return Ok(list);
+        }
+        let stale = PROXY_LIST.last();
+
+        let kv = env.kv("SIREN")?;
+        let cached = kv.get("proxy_kv").text().await.unwrap_or_else(|e| {
+            console_error!("[proxy list]: reading the cached list: {}", e);
+            None
+        });
+        let backup = async { Ok(kv.get("proxy_kv_backup").text().await?) };
+        let mut errors = Vec::new();
+        let list = Self::fallback(cached, Self::refresh(env, &kv), stale.is_some(), backup, &mut errors).await;
+        for e in errors {
+            console_error!("[proxy list]: {}", e);
+        }
+        let mut list = match list? {
+            Some(list) => list,
+            None => {
+                let stale = stale.unwrap_or_default();
+                PROXY_LIST.store(now, stale.clone());
+                return Ok(stale);
+            }
+        };
+
+        let aliases_str = kv.get("proxy_aliases").text().await?.unwrap_or_default();
+        if !aliases_str.is_empty() {
+            match parse_aliases(&aliases_str) {
+                Ok(aliases) => list.aliases = aliases,
+                Err(e) => console_error!("[proxy list]: ignoring malformed aliases: {}", e),
+            }
+        }
+
+        let list = Arc::new(list);
+        PROXY_LIST.store(now, list.clone());
+        Ok(list)
+    }
+
+    // the cached list when it parses, else a refresh from the sources, else
+    // None to keep serving the stale isolate copy when there is one, else the
+    // backup. Errors met on the way are collected for the log.
+    async fn fallback(
+        cached: Option<String>,
+        refresh: impl Future<Output = Result<Self>>,
+        has_stale: bool,
+        backup: impl Future<Output = Result<Option<String>>>,
+        errors: &mut Vec<Error>,
+    ) -> Result<Option<Self>> {
+        match cached.filter(|list_str| !list_str.is_empty()).map(|list_str| Self::parse(&list_str)) {
+            Some(Ok(list)) => return Ok(Some(list)),
+            Some(Err(e)) => errors.push(Error::RustError(format!("cached list is malformed, refreshing: {}", e))),
+            None => {}
+        }
+
+        let refresh_error = match refresh.await {
+            Ok(list) => return Ok(Some(list)),
+            Err(e) => e,
+        };
+        errors.push(Error::RustError(format!("refresh failed, serving the last good list: {}", refresh_error)));
+        if has_stale {
+            return Ok(None);
+        }
+
+        match backup.await {
+            Ok(Some(list_str)) if !list_str.is_empty() => match Self::parse(&list_str) {
+                Ok(list) => return Ok(Some(list)),
+                Err(e) => errors.push(Error::RustError(format!("backup list is malformed: {}", e))),
+            },
+            Ok(_) => {}
+            Err(e) => errors.push(Error::RustError(format!("reading the backup list: {}", e))),
+        }
+        Err(refresh_error)
+    }
+
+    async fn refresh(env: &Env, kv: &kv::KvStore) -> Result<Self> {
+        let sources = env
+            .var("PROXY_LIST_SOURCES")
+            .map(|x| x.to_string())
+            .unwrap_or_else(|_| DEFAULT_SOURCE.to_string());
+
+        let mut list = Self::default();
+        let mut fetched = 0;
+        for source in sources.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            console_log!("getting proxy list from {}...", source);
+            let list_str = match fetch_source(kv, source).await {
+                Ok(list_str) => list_str,
+                Err(e) => {
+                    console_error!("[proxy list]: {}: {}", source, e);
+                    continue;
+                }
+            };
+            let invalid = match list.merge(&list_str) {
+                Ok(invalid) => invalid,
+                Err(e) => {
+                    console_error!("[proxy list]: {} is malformed: {}", source, e);
+                    continue;
+                }
+            };
+            if !invalid.is_empty() {
+                console_warn!(
+                    "[proxy list]: skipped {} invalid entries from {}, e.g. {}",
+                    invalid.len(),
+                    source,
+                    invalid.iter().take(MAX_REPORTED).cloned().collect::<Vec<_>>().join(", ")
+                );
+            }
+            fetched += 1;
+        }
+        if fetched == 0 {
+            return Err(Error::RustError("no proxy list source could be loaded".to_string()));
+        }
+
+        let list_str = list.to_json();
+        kv.put("proxy_kv", &list_str)?.expiration_ttl(CACHE_TTL).execute().await?;
+        kv.put("proxy_kv_backup", &list_str)?.execute().await?;
+        Ok(list)
+    }
+
+    fn parse(list_str: &str) -> Result<Self> {
+        let mut list = Self::default();
+        list.merge(list_str)?;
+        Ok(list)
+    }
+
+    // adds the valid entries of a source, returning the invalid ones
+    fn merge(&mut self, list_str: &str) -> Result<Vec<String>> {
+        let source: HashMap<String, Vec<Entry>> = serde_json::from_str(list_str)?;
+
+        let mut invalid = Vec::new();
+        for (country, entries) in source {
+            if country.len() != 2 || !country.bytes().all(|b| b.is_ascii_alphabetic()) {
+                invalid.push(format!("country {}", country));
+                continue;
+            }
+
+            let proxies = self.countries.entry(country.to_ascii_uppercase()).or_default();
+            let mut seen: HashSet<(String, u16)> = proxies.iter().cloned().collect();
+            for entry in entries {
+                let (entry, isp) = match entry {
+                    Entry::Proxy(proxy) => (proxy, None),
+                    Entry::Detailed { proxy, isp } => (proxy, Some(isp)),
+                };
+                match parse_host_port(&entry).filter(|(addr, port)| addr.parse::<IpAddr>().is_ok() && *port != 0) {
+                    Some(proxy) => {
+                        if let Some(isp) = isp {
+                            self.isps.entry(proxy.clone()).or_insert(isp);
+                        }
+                        if seen.insert(proxy.clone()) {
+                            proxies.push(proxy);
+                        }
+                    }
+                    None => invalid.push(entry),
+                }
+            }
+        }
+        self.countries.retain(|_, proxies| !proxies.is_empty());
+
+        Ok(invalid)
+    }
+
+    fn to_json(&self) -> String {
+        let countries: HashMap<&String, Vec<Entry>> = self
+            .countries
+            .iter()
+            .map(|(country, proxies)| {
+                let entries = proxies
+                    .iter()
+                    .map(|(addr, port)| {
+                        let proxy = if addr.contains(':') {
+                            format!("[{}]:{}", addr, port)
+                        } else {
+                            format!("{}:{}", addr, port)
+                        };
+                        match self.isps.get(&(addr.clone(), *port)) {
+                            Some(isp) => Entry::Detailed { proxy, isp: isp.clone() },
+                            None => Entry::Proxy(proxy),
+                        }
+                    })
+                    .collect();
+                (country, entries)
+            })
+            .collect();
+        serde_json::to_string(&countries).unwrap_or_default()
+    }
+
+    // every listed country code, sorted
+    pub fn country_codes(&self) -> Vec<String> {
+        let mut countries: Vec<String> = self.countries.keys().cloned().collect();
+        countries.sort();
+        countries
+    }
+
+    // proxy ips of `country`, only those of a matching isp when given
+    pub fn proxies(&self, country: &str, isp: Option<&str>) -> Vec<(String, u16)> {
+        let Some(proxies) = self.countries.get(country) else {
+            return Vec::new();
+        };
+        let Some(isp) = isp else {
+            return proxies.clone();
+        };
+        proxies
+            .iter()
+            .filter(|proxy| {
+                self.isps
+                    .get(*proxy)
+                    .is_some_and(|name| name.to_ascii_lowercase().contains(isp))
+            })
+            .cloned()
+            .collect()
+    }
+
+    // country codes named by an alias or a listed country code
+    fn expand(&self, name: &str) -> Option<Vec<String>> {
+        let upper = name.to_ascii_uppercase();
+        match self.aliases.get(&upper) {
+            Some(members) => Some(members.clone()),
+            None if self.countries.contains_key(&upper) => Some(vec![upper]),
+            None => None,
+        }
+    }
+
+    // weighted countries matching `query`, each with proxy ips left after the
+    // isp filter. Alias members without proxy ips are skipped, while a name
+    // that is neither an alias nor a listed country fails the query.
+    pub fn select(&self, query: &SelectionQuery) -> std::result::Result<Vec<(String, u32)>, String> {
+        let unknown = |name: &str| format!("unknown country code or alias: {}", name);
+
+        let mut countries: Vec<(String, u32)> = Vec::new();
+        if query.include.is_empty() {
+            countries = self.country_codes().into_iter().map(|country| (country, 1)).collect();
+        }
+        for (name, weight) in query.include.iter() {
+            for country in self.expand(name).ok_or_else(|| unknown(name))? {
+                if !countries.iter().any(|(c, _)| *c == country) {
+                    countries.push((country, *weight));
+                }
+            }
+        }
+        for name in query.exclude.iter() {
+            let excluded = self.expand(name).ok_or_else(|| unknown(name))?;
+            countries.retain(|(country, _)| !excluded.contains(country));
+        }
+        countries.retain(|(country, _)| !self.proxies(country, query.isp.as_deref()).is_empty());
+
+        if countries.is_empty() {
+            return Err("no proxy ip matches the selection".to_string());
+        }
+        Ok(countries)
+    }
+}
+
+fn parse_aliases(aliases_str: &str) -> Result<HashMap<String, Vec<String>>> {
+    let aliases: HashMap<String, Vec<String>> = serde_json::from_str(aliases_str)?;
+    Ok(aliases
+        .into_iter()
+        .map(|(alias, codes)| {
+            let codes = codes.iter().map(|code| code.to_ascii_uppercase()).collect();
+            (alias.to_ascii_uppercase(), codes)
+        })
+        .collect())
+}
+
+async fn fetch_source(kv: &kv::KvStore, source: &str) -> Result<String> {
+    if let Some(key) = source.strip_prefix("kv:") {
+        return kv
+            .get(key)
+            .text()
+            .await?
+            .ok_or_else(|| Error::RustError(format!("kv key {} is empty", key)));
+    }
+
+    let mut res = Fetch::Url(Url::parse(source)?).send().await?;
+    if res.status_code() != 200 {
+        return Err(Error::RustError(format!("responded with {}", res.status_code())));
+    }
+    res.text().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut list = ProxyList::default();
+        let mut invalid = list
+            .merge(r#"{"SG": ["1.2.3.4:443", "1.2.3.4:443", "bad", "[2001:db8::1]:8443"], "Singapore": ["5.6.7.8:443"]}"#)
+            .unwrap();
+        invalid.sort();
+        assert_eq!(invalid, vec!["bad".to_string(), "country Singapore".to_string()]);
+
+        let invalid = list.merge(r#"{"sg": ["1.2.3.4:443", "9.9.9.9:443"], "ID": ["host.example:443"]}"#).unwrap();
+        assert_eq!(invalid, vec!["host.example:443".to_string()]);
+        assert_eq!(
+            list.proxies("SG", None),
+            vec![
+                ("1.2.3.4".to_string(), 443),
+                ("2001:db8::1".to_string(), 8443),
+                ("9.9.9.9".to_string(), 443)
+            ]
+        );
+        assert!(list.proxies("ID", None).is_empty());
+
+        let list = ProxyList::parse(&list.to_json()).unwrap();
+        assert_eq!(list.proxies("SG", None).len(), 3);
+        assert!(ProxyList::parse("not json").is_err());
+    }
+
+    #[test]
+    fn test_fallback() {
+        let list_str = r#"{"SG": ["1.2.3.4:443"]}"#;
+        let corrupt = || Some(r#"{"SG": ["1.2.3.4:443""#.to_string());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let fallback = |cached, refresh_ok: bool, has_stale, backup: Option<&str>| {
+            let backup = backup.map(|backup| backup.to_string());
+            runtime.block_on(async move {
+                let refresh = async move {
+                    if refresh_ok {
+                        ProxyList::parse(r#"{"JP": ["5.6.7.8:443"]}"#)
+                    } else {
+                        Err(Error::RustError("no proxy list source could be loaded".to_string()))
+                    }
+                };
+                let mut errors = Vec::new();
+                let list = ProxyList::fallback(cached, refresh, has_stale, async { Ok(backup) }, &mut errors).await;
+                (list, errors.len())
+            })
+        };
+
+        let (list, errors) = fallback(Some(list_str.to_string()), false, false, None);
+        assert_eq!(list.unwrap().unwrap().country_codes(), vec!["SG"]);
+        assert_eq!(errors, 0);
+
+        // a corrupt cached list is refreshed, then the stale copy is kept,
+        // then the backup is used
+        let (list, errors) = fallback(corrupt(), true, true, None);
+        assert_eq!(list.unwrap().unwrap().country_codes(), vec!["JP"]);
+        assert_eq!(errors, 1);
+        let (list, _) = fallback(corrupt(), false, true, Some(list_str));
+        assert!(list.unwrap().is_none());
+        let (list, errors) = fallback(corrupt(), false, false, Some(list_str));
+        assert_eq!(list.unwrap().unwrap().country_codes(), vec!["SG"]);
+        assert_eq!(errors, 2);
+        let (list, _) = fallback(corrupt(), false, false, Some("not json"));
+        assert!(list.is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let mut list = ProxyList::parse(
+            r#"{"SG": ["1.2.3.4:443", {"proxy": "1.2.3.5:443", "isp": "Singtel"}], "JP": ["5.6.7.8:443"], "CN": ["9.9.9.9:443"]}"#,
+        )
+        .unwrap();
+        list.aliases = parse_aliases(r#"{"asia": ["sg", "jp", "cn", "kr"]}"#).unwrap();
+        let select = |query: &str| list.select(&query.parse().unwrap());
+
+        assert_eq!(
+            select("SG:3,Asia,!cn"),
+            Ok(vec![("SG".to_string(), 3), ("JP".to_string(), 1)])
+        );
+        assert_eq!(select("!SG,!JP"), Ok(vec![("CN".to_string(), 1)]));
+        assert_eq!(select("ASIA@singtel"), Ok(vec![("SG".to_string(), 1)]));
+        assert_eq!(list.proxies("SG", Some("singtel")), vec![("1.2.3.5".to_string(), 443)]);
+        assert!(select("SG,XX").is_err());
+        assert!(select("JP@singtel").is_err());
+
+        let list = ProxyList::parse(&list.to_json()).unwrap();
+        assert_eq!(list.proxies("SG", Some("singtel")).len(), 1);
+    }
+}
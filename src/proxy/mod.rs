@@ -3,5 +3,13 @@ pub mod vless;
 pub mod trojan;
 pub mod shadowsocks;
 pub mod dns;
+pub mod codec;
+pub mod route;
+pub mod policy;
+pub mod cloudflare;
+pub mod pool;
+pub mod list;
+pub mod query;
+pub mod geo;
 pub mod conn;
 pub use conn::*;
@@ -0,0 +1,100 @@
+use crate::common::cidr::Cidr;
+
+use std::net::IpAddr;
+use once_cell::sync::Lazy;
+use worker::*;
+
+// destinations a client must not reach through the worker unless allowlisted
+static BLOCKED_RANGES: Lazy<Vec<Cidr>> = Lazy::new(|| {
+    [
+        // this network, rfc1918, cgnat (also alibaba cloud metadata)
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        // loopback, link-local (also aws/gcp/azure metadata)
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        // ietf protocol assignments, benchmarking
+        "192.0.0.0/24",
+        "198.18.0.0/15",
+        // multicast, reserved and broadcast
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        // unspecified, loopback, unique local (also aws ipv6 metadata),
+        // link-local, multicast
+        "::/128",
+        "::1/128",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|cidr| cidr.parse().unwrap())
+    .collect()
+});
+
+#[derive(Default)]
+pub struct DestinationPolicy {
+    // ranges exempt from BLOCKED_RANGES
+    allow: Vec<Cidr>,
+}
+
+impl DestinationPolicy {
+    // `ALLOWED_DESTINATIONS` holds comma separated cidrs or addresses, a
+    // malformed list allows nothing beyond the defaults
+    pub fn from_env(env: &Env) -> Self {
+        let allow = env
+            .var("ALLOWED_DESTINATIONS")
+            .map(|x| x.to_string())
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse())
+            .collect::<Result<_>>();
+
+        match allow {
+            Ok(allow) => Self { allow },
+            Err(e) => {
+                console_error!("[policy]: ignoring ALLOWED_DESTINATIONS: {}", e);
+                Self::default()
+            }
+        }
+    }
+
+    // only literal addresses and localhost names are checked, resolving a
+    // domain here would not bind the address the socket later connects to
+    pub fn permits(&self, addr: &str) -> bool {
+        let ip = match addr.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => ip.to_canonical(),
+            Err(_) => {
+                let domain = addr.trim_end_matches('.').to_ascii_lowercase();
+                return domain != "localhost" && !domain.ends_with(".localhost");
+            }
+        };
+
+        self.allow.iter().any(|cidr| cidr.contains(&ip))
+            || !BLOCKED_RANGES.iter().any(|cidr| cidr.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permits() {
+        let policy = DestinationPolicy {
+            allow: vec!["10.1.0.0/16".parse().unwrap()],
+        };
+
+        for addr in ["127.0.0.1", "169.254.169.254", "192.168.1.1", "100.100.100.200", "::1", "::ffff:10.0.0.1", "fd00:ec2::254", "localhost"] {
+            assert!(!policy.permits(addr), "{}", addr);
+        }
+        for addr in ["1.1.1.1", "10.1.2.3", "2606:4700::1111", "example.com"] {
+            assert!(policy.permits(addr), "{}", addr);
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use crate::common::unix_timestamp;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use worker::kv::KvStore;
+use worker::*;
+
+// consecutive failures before a proxy ip is skipped
+const FAILURE_THRESHOLD: u32 = 3;
+// seconds a tripped proxy ip is skipped before it gets another try
+const COOLDOWN: u64 = 300;
+// proxy ips tried per connection
+pub const MAX_PROXY_ATTEMPTS: usize = 3;
+// proxy ips tracked per isolate
+const MAX_ENTRIES: usize = 1024;
+// seconds after which an untripped proxy ip may be forgotten
+const IDLE_TIMEOUT: u64 = 60 * 60;
+
+// weight of the latest sample in the latency average
+const LATENCY_SMOOTHING: f64 = 0.3;
+// SIREN kv key prefix of tripped proxy ips
+const KV_PREFIX: &str = "proxy_health:";
+// kv rejects expirations less than a minute away
+const MIN_KV_TTL: u64 = 60;
+
+pub static PROXY_HEALTH: Lazy<Mutex<ProxyHealth>> = Lazy::new(|| Mutex::new(ProxyHealth::default()));
+
+// next round-robin position per proxy list
+static ROUND_ROBIN: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Default)]
+struct Health {
+    failures: u32,
+    // the circuit is open, and the proxy ip skipped, until then
+    open_until: u64,
+    // moving average of the connect time in milliseconds, 0 when unmeasured
+    latency: f64,
+    // last success or failure
+    updated: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    #[default]
+    Random,
+    RoundRobin,
+    // the same client ip keeps the same exit
+    Sticky,
+    // random, favouring proxy ips that connect faster
+    Latency,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "random" => Ok(Strategy::Random),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "sticky" => Ok(Strategy::Sticky),
+            "latency" => Ok(Strategy::Latency),
+            _ => Err(Error::RustError(format!("unknown proxy strategy: {}", s))),
+        }
+    }
+}
+
+impl Strategy {
+    // index of the first pick among `len` choices of the list named `scope`
+    pub fn start(&self, scope: &str, client_ip: Option<&str>, len: usize) -> usize {
+        match (self, client_ip) {
+            (Strategy::RoundRobin, _) => {
+                let mut positions = ROUND_ROBIN.lock().unwrap();
+                let position = positions.entry(scope.to_string()).or_default();
+                let start = *position % len;
+                *position = start + 1;
+                start
+            }
+            (Strategy::Sticky, Some(client_ip)) => (fnv1a(client_ip) ^ fnv1a(scope)) as usize % len,
+            _ => random_u64() as usize % len,
+        }
+    }
+
+    // index of the first pick among choices weighted by `weights`
+    pub fn start_weighted(&self, scope: &str, client_ip: Option<&str>, weights: &[u32]) -> usize {
+        // summed in u64, usize is 32 bits on wasm
+        let total = weights.iter().map(|weight| *weight as u64).sum::<u64>().max(1);
+        let mut slot = self.start(scope, client_ip, usize::try_from(total).unwrap_or(usize::MAX)) as u64;
+        for (index, weight) in weights.iter().enumerate() {
+            if slot < *weight as u64 {
+                return index;
+            }
+            slot -= *weight as u64;
+        }
+        weights.len().saturating_sub(1)
+    }
+
+    // `proxies` in the order they should be tried
+    pub fn order(&self, scope: &str, client_ip: Option<&str>, mut proxies: Vec<(String, u16)>) -> Vec<(String, u16)> {
+        if proxies.is_empty() {
+            return proxies;
+        }
+        if *self == Strategy::Latency {
+            return PROXY_HEALTH.lock().unwrap().order_by_latency(proxies, random_unit);
+        }
+
+        let start = self.start(scope, client_ip, proxies.len());
+        proxies.rotate_left(start);
+        proxies
+    }
+}
+
+fn random_u64() -> u64 {
+    let mut rand_buf = [0u8; 8];
+    getrandom::getrandom(&mut rand_buf).expect("failed generating random number");
+    u64::from_be_bytes(rand_buf)
+}
+
+// uniform in [0, 1)
+fn random_unit() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// stable across isolates, unlike the std hasher
+fn fnv1a(s: &str) -> u64 {
+    s.bytes()
+        .fold(0xcbf29ce484222325, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+// isolate-local circuit breaker per proxy ip. Once the cooldown of a tripped
+// proxy ip runs out it is tried again, and a single failure trips it anew
+// until a success resets it.
+#[derive(Default)]
+pub struct ProxyHealth {
+    entries: HashMap<String, Health>,
+    // proxy ips tripped, with their open until, or recovered, with 0, since
+    // the last persist. Only tracked once restored from kv.
+    pending: HashMap<String, u64>,
+    restored: bool,
+}
+
+fn key(addr: &str, port: u16) -> String {
+    format!("{}:{}", addr, port)
+}
+
+impl ProxyHealth {
+    pub fn is_available(&self, addr: &str, port: u16, now: u64) -> bool {
+        self.entries
+            .get(&key(addr, port))
+            .is_none_or(|health| health.open_until <= now)
+    }
+
+    pub fn record_success(&mut self, addr: &str, port: u16, latency: u64, now: u64) {
+        let health = self.entry(key(addr, port), now);
+        let recovered = health.open_until > 0;
+        health.updated = now;
+        health.failures = 0;
+        health.open_until = 0;
+        health.latency = if health.latency == 0.0 {
+            latency as f64
+        } else {
+            health.latency + LATENCY_SMOOTHING * (latency as f64 - health.latency)
+        };
+        if recovered && self.restored {
+            self.pending.insert(key(addr, port), 0);
+        }
+    }
+
+    // returns whether the proxy ip got tripped
+    pub fn record_failure(&mut self, addr: &str, port: u16, now: u64) -> bool {
+        let health = self.entry(key(addr, port), now);
+        health.updated = now;
+        health.failures += 1;
+        if health.failures < FAILURE_THRESHOLD {
+            return false;
+        }
+        health.open_until = now + COOLDOWN;
+        if self.restored {
+            self.pending.insert(key(addr, port), now + COOLDOWN);
+        }
+        true
+    }
+
+    // makes room by forgetting idle untripped proxy ips first, then the least
+    // recently updated one
+    fn entry(&mut self, key: String, now: u64) -> &mut Health {
+        if !self.entries.contains_key(&key) && self.entries.len() >= MAX_ENTRIES {
+            self.entries
+                .retain(|_, health| health.open_until > now || now.saturating_sub(health.updated) < IDLE_TIMEOUT);
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= MAX_ENTRIES {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, health)| health.updated)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.entry(key).or_default()
+    }
+
+    // up to MAX_PROXY_ATTEMPTS proxy ips in the given order, skipping tripped
+    // ones unless every one of them is tripped
+    pub fn candidates(&self, proxies: &[(String, u16)], now: u64) -> Vec<(String, u16)> {
+        let available: Vec<_> = proxies
+            .iter()
+            .filter(|(addr, port)| self.is_available(addr, *port, now))
+            .take(MAX_PROXY_ATTEMPTS)
+            .cloned()
+            .collect();
+        if available.is_empty() {
+            return proxies.iter().take(1).cloned().collect();
+        }
+        available
+    }
+
+    // weighted shuffle by inverse latency, unmeasured proxy ips are weighted
+    // as the average measured one
+    fn order_by_latency(&self, mut proxies: Vec<(String, u16)>, mut random: impl FnMut() -> f64) -> Vec<(String, u16)> {
+        let latencies: Vec<f64> = proxies
+            .iter()
+            .map(|(addr, port)| self.entries.get(&key(addr, *port)).map_or(0.0, |health| health.latency))
+            .collect();
+        let measured: Vec<f64> = latencies.iter().copied().filter(|latency| *latency > 0.0).collect();
+        let average = if measured.is_empty() {
+            1.0
+        } else {
+            measured.iter().sum::<f64>() / measured.len() as f64
+        };
+        let mut weights: Vec<f64> = latencies
+            .iter()
+            .map(|latency| 1.0 / if *latency > 0.0 { *latency } else { average })
+            .collect();
+
+        let mut ordered = Vec::with_capacity(proxies.len());
+        while !proxies.is_empty() {
+            let mut target = random() * weights.iter().sum::<f64>();
+            let mut index = weights.len() - 1;
+            for (i, weight) in weights.iter().enumerate() {
+                if target < *weight {
+                    index = i;
+                    break;
+                }
+                target -= weight;
+            }
+            weights.remove(index);
+            ordered.push(proxies.remove(index));
+        }
+        ordered
+    }
+}
+
+// tripped proxy ips as `proxy_health:1.2.3.4:443` keys in SIREN kv that
+// expire with the cooldown, so that fresh isolates skip them as well. Every
+// isolate writes only the proxy ips it saw change.
+pub async fn restore(kv: &KvStore) -> Result<()> {
+    if PROXY_HEALTH.lock().unwrap().restored {
+        return Ok(());
+    }
+
+    let keys = kv.list().prefix(KV_PREFIX.to_string()).execute().await?.keys;
+    let now = unix_timestamp();
+    let mut health = PROXY_HEALTH.lock().unwrap();
+    for entry in keys {
+        let (Some(key), Some(open_until)) = (entry.name.strip_prefix(KV_PREFIX), entry.expiration) else {
+            continue;
+        };
+        let entry = health.entry(key.to_string(), now);
+        entry.failures = entry.failures.max(FAILURE_THRESHOLD);
+        entry.open_until = entry.open_until.max(open_until);
+    }
+    health.restored = true;
+    Ok(())
+}
+
+pub async fn persist(kv: &KvStore) -> Result<()> {
+    let now = unix_timestamp();
+    let pending = std::mem::take(&mut PROXY_HEALTH.lock().unwrap().pending);
+
+    for (key, open_until) in pending {
+        let name = format!("{}{}", KV_PREFIX, key);
+        if open_until >= now + MIN_KV_TTL {
+            kv.put(&name, "")?.expiration(open_until).execute().await?;
+        } else {
+            kv.delete(&name).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let proxies = vec![("1.1.1.1".to_string(), 443), ("2.2.2.2".to_string(), 443)];
+        let mut health = ProxyHealth {
+            restored: true,
+            ..Default::default()
+        };
+
+        for _ in 0..FAILURE_THRESHOLD {
+            assert!(health.is_available("1.1.1.1", 443, 1000));
+            health.record_failure("1.1.1.1", 443, 1000);
+        }
+        assert_eq!(health.candidates(&proxies, 1000), vec![proxies[1].clone()]);
+        assert_eq!(health.pending.get("1.1.1.1:443"), Some(&(1000 + COOLDOWN)));
+
+        // half open after the cooldown, a single failure trips it again
+        assert!(health.is_available("1.1.1.1", 443, 1000 + COOLDOWN));
+        health.record_failure("1.1.1.1", 443, 1000 + COOLDOWN);
+        assert!(!health.is_available("1.1.1.1", 443, 1001 + COOLDOWN));
+
+        health.record_failure("2.2.2.2", 443, 1000 + COOLDOWN);
+        health.record_success("1.1.1.1", 443, 100, 1001 + COOLDOWN);
+        assert_eq!(health.candidates(&proxies, 1001 + COOLDOWN), proxies);
+        // recovered, its kv entry is dropped on the next persist
+        assert_eq!(health.pending.get("1.1.1.1:443"), Some(&0));
+    }
+
+    #[test]
+    fn test_bounded_entries() {
+        let mut health = ProxyHealth::default();
+        for i in 0..MAX_ENTRIES {
+            health.record_success(&format!("10.0.{}.{}", i / 256, i % 256), 443, 100, 1000);
+        }
+        health.record_success("1.1.1.1", 443, 100, 1001);
+        assert_eq!(health.entries.len(), MAX_ENTRIES);
+
+        // idle untripped proxy ips are forgotten first
+        for _ in 0..FAILURE_THRESHOLD {
+            health.record_failure("2.2.2.2", 443, 1000 + IDLE_TIMEOUT);
+        }
+        for i in 0..MAX_ENTRIES - 2 {
+            health.record_success(&format!("10.1.{}.{}", i / 256, i % 256), 443, 100, 1000 + IDLE_TIMEOUT);
+        }
+        health.record_success("3.3.3.3", 443, 100, 1001 + IDLE_TIMEOUT);
+        assert!(!health.is_available("2.2.2.2", 443, 1001 + IDLE_TIMEOUT));
+        assert!(health.entries.len() <= MAX_ENTRIES);
+    }
+
+    #[test]
+    fn test_order_by_latency() {
+        let proxies = vec![("1.1.1.1".to_string(), 443), ("2.2.2.2".to_string(), 443)];
+        let mut health = ProxyHealth::default();
+        health.record_success("1.1.1.1", 443, 300, 1000);
+        health.record_success("2.2.2.2", 443, 100, 1000);
+
+        // weights 1/300 and 1/100, the faster one takes the upper 3/4
+        assert_eq!(health.order_by_latency(proxies.clone(), || 0.2), proxies);
+        assert_eq!(health.order_by_latency(proxies.clone(), || 0.3)[0], proxies[1]);
+    }
+
+    // scopes are unique to each test, ROUND_ROBIN is shared by the process
+    #[test]
+    fn test_round_robin() {
+        assert_eq!(Strategy::RoundRobin.start("test_round_robin", None, 2), 0);
+        assert_eq!(Strategy::RoundRobin.start("test_round_robin", None, 2), 1);
+        assert_eq!(Strategy::RoundRobin.start("test_round_robin", None, 2), 0);
+
+        let weighted: Vec<usize> = (0..4)
+            .map(|_| Strategy::RoundRobin.start_weighted("test_round_robin_weighted", None, &[3, 1]))
+            .collect();
+        assert_eq!(weighted, vec![0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_sticky() {
+        let sticky = Strategy::Sticky.start("test_sticky", Some("203.0.113.7"), 10);
+        assert_eq!(Strategy::Sticky.start("test_sticky", Some("203.0.113.7"), 10), sticky);
+
+        let proxies: Vec<(String, u16)> = (0..10).map(|i| (format!("10.0.0.{}", i), 443)).collect();
+        let ordered = Strategy::Sticky.order("test_sticky", Some("203.0.113.7"), proxies.clone());
+        assert_eq!(ordered[0], proxies[sticky]);
+        assert_eq!(Strategy::Sticky.order("test_sticky", Some("203.0.113.7"), proxies), ordered);
+    }
+}
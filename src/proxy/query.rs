@@ -0,0 +1,89 @@
+use std::str::FromStr;
+use worker::*;
+
+// largest weight a term may carry
+const MAX_WEIGHT: u32 = 1000;
+
+// the `:proxyip` segment of the tunnel path when it names countries
+//
+//   query := term ("," term)* ["@" isp]
+//   term  := ["!"] name [":" weight]
+//
+// names are country codes or aliases. `SG:3,ID:1` picks SG three times as
+// often as ID, `ASIA,!CN` is every asian country but CN, an exclusion-only
+// query starts from every listed country, and `SG@telkom` keeps the proxy ips
+// whose isp contains "telkom".
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SelectionQuery {
+    pub include: Vec<(String, u32)>,
+    pub exclude: Vec<String>,
+    pub isp: Option<String>,
+}
+
+impl FromStr for SelectionQuery {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::RustError(format!("invalid selection {}: {}", s, reason));
+
+        let (terms, isp) = match s.split_once('@') {
+            Some((terms, isp)) if !isp.trim().is_empty() => (terms, Some(isp.trim().to_ascii_lowercase())),
+            Some(_) => return Err(invalid("empty isp")),
+            None => (s, None),
+        };
+
+        let mut query = Self {
+            isp,
+            ..Default::default()
+        };
+        for term in terms.split(',').map(|term| term.trim()) {
+            if let Some(name) = term.strip_prefix('!') {
+                if name.is_empty() || name.contains(':') {
+                    return Err(invalid("exclusions take a bare name"));
+                }
+                query.exclude.push(name.to_string());
+                continue;
+            }
+
+            let (name, weight) = match term.split_once(':') {
+                Some((name, weight)) => match weight.parse() {
+                    Ok(weight) if (1..=MAX_WEIGHT).contains(&weight) => (name, weight),
+                    _ => return Err(invalid("weights are integers from 1 to 1000")),
+                },
+                None => (term, 1),
+            };
+            if name.is_empty() {
+                return Err(invalid("empty name"));
+            }
+            query.include.push((name.to_string(), weight));
+        }
+
+        Ok(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let query: SelectionQuery = "SG:3,ID,!CN@Telkom".parse().unwrap();
+        assert_eq!(
+            query,
+            SelectionQuery {
+                include: vec![("SG".to_string(), 3), ("ID".to_string(), 1)],
+                exclude: vec!["CN".to_string()],
+                isp: Some("telkom".to_string()),
+            }
+        );
+
+        assert!("SG:0".parse::<SelectionQuery>().is_err());
+        assert!("SG:1000".parse::<SelectionQuery>().is_ok());
+        assert!("SG:1001".parse::<SelectionQuery>().is_err());
+        assert!("SG:4294967295".parse::<SelectionQuery>().is_err());
+        assert!("SG,,ID".parse::<SelectionQuery>().is_err());
+        assert!("!CN:2".parse::<SelectionQuery>().is_err());
+        assert!("SG@".parse::<SelectionQuery>().is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use crate::common::cidr::Cidr;
+use crate::common::cache::IsolateCache;
+use crate::config::{Protocol, User};
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use regex::Regex;
+use serde::Deserialize;
+use worker::*;
+
+static ROUTE_TABLE: IsolateCache<RouteTable> = IsolateCache::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    // connect to the requested destination
+    Direct,
+    // connect through the proxy ip picked from the tunnel path
+    Proxy,
+    // connect through one of the `host:port` entries of a named group
+    Group(String),
+    Block,
+}
+
+pub struct Destination<'a> {
+    pub addr: &'a str,
+    pub port: u16,
+    pub protocol: Protocol,
+    pub user: Option<&'a User>,
+}
+
+// every non-empty condition must match, and a condition matches when any of
+// its entries does
+#[derive(Deserialize)]
+pub struct Rule {
+    #[serde(default)]
+    domain: Vec<String>,
+    #[serde(default)]
+    domain_suffix: Vec<String>,
+    #[serde(default)]
+    domain_keyword: Vec<String>,
+    #[serde(default)]
+    domain_regex: Vec<String>,
+    #[serde(skip)]
+    regexes: Vec<Regex>,
+    #[serde(default)]
+    ip_cidr: Vec<Cidr>,
+    // single ports or inclusive `from-to` ranges
+    #[serde(default)]
+    port: Vec<String>,
+    #[serde(default)]
+    protocol: Vec<Protocol>,
+    // user labels or ids
+    #[serde(default)]
+    user: Vec<String>,
+    pub action: Action,
+}
+
+impl Rule {
+    fn matches(&self, dest: &Destination) -> bool {
+        let ip: Option<IpAddr> = dest.addr.parse().ok();
+        let domain = match ip {
+            Some(_) => None,
+            None => Some(dest.addr.trim_end_matches('.').to_ascii_lowercase()),
+        };
+
+        let has_domain_rule = !self.domain.is_empty()
+            || !self.domain_suffix.is_empty()
+            || !self.domain_keyword.is_empty()
+            || !self.regexes.is_empty();
+        if has_domain_rule {
+            let Some(domain) = domain.as_deref() else {
+                return false;
+            };
+            let matched = self.domain.iter().any(|d| d == domain)
+                || self.domain_suffix.iter().any(|s| {
+                    domain == s || domain.strip_suffix(s.as_str()).is_some_and(|x| x.ends_with('.'))
+                })
+                || self.domain_keyword.iter().any(|k| domain.contains(k.as_str()))
+                || self.regexes.iter().any(|r| r.is_match(domain));
+            if !matched {
+                return false;
+            }
+        }
+
+        if !self.ip_cidr.is_empty() {
+            let Some(ip) = ip else {
+                return false;
+            };
+            if !self.ip_cidr.iter().any(|cidr| cidr.contains(&ip)) {
+                return false;
+            }
+        }
+
+        if !self.port.is_empty() && !self.port.iter().any(|p| port_matches(p, dest.port)) {
+            return false;
+        }
+
+        if !self.protocol.is_empty() && !self.protocol.contains(&dest.protocol) {
+            return false;
+        }
+
+        if !self.user.is_empty() {
+            let Some(user) = dest.user else {
+                return false;
+            };
+            let id = user.id.to_string();
+            if !self.user.iter().any(|u| *u == user.label || *u == id) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+fn port_matches(range: &str, port: u16) -> bool {
+    match range.split_once('-') {
+        Some((from, to)) => match (from.trim().parse::<u16>(), to.trim().parse::<u16>()) {
+            (Ok(from), Ok(to)) => (from..=to).contains(&port),
+            _ => false,
+        },
+        None => range.trim().parse() == Ok(port),
+    }
+}
+
+// splits `host:port`, with ipv6 hosts written as `[addr]:port`
+pub fn parse_host_port(s: &str) -> Option<(String, u16)> {
+    let (host, port) = s.trim().rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+// `routes` key in SIREN kv:
+// {
+//   "rules": [
+//     { "domain_suffix": ["example.com"], "port": ["443"], "action": "direct" },
+//     { "ip_cidr": ["1.0.0.0/8"], "action": { "group": "sg" } },
+//     { "domain_keyword": ["ads"], "action": "block" }
+//   ],
+//   "groups": { "sg": ["1.2.3.4:443", "5.6.7.8:443"] },
+//   "default": "proxy"
+// }
+//
+// without a matching rule or default the destination is tried directly and
+// then through the proxy ip
+#[derive(Default, Deserialize)]
+pub struct RouteTable {
+    #[serde(default)]
+    rules: Vec<Rule>,
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    default: Option<Action>,
+}
+
+impl RouteTable {
+    // a malformed or unreadable table keeps the last good one, or none
+    pub async fn load(env: &Env) -> Arc<Self> {
+        ROUTE_TABLE.load("routes", Self::fetch(env)).await
+    }
+
+    async fn fetch(env: &Env) -> Result<Self> {
+        let table_str = env.kv("SIREN")?.get("routes").text().await?.unwrap_or_default();
+        if table_str.is_empty() {
+            return Ok(Self::default());
+        }
+        Self::parse(&table_str)
+    }
+
+    fn parse(table_str: &str) -> Result<Self> {
+        let mut table: Self = serde_json::from_str(table_str)?;
+        for rule in table.rules.iter_mut() {
+            for domain in rule
+                .domain
+                .iter_mut()
+                .chain(rule.domain_suffix.iter_mut())
+                .chain(rule.domain_keyword.iter_mut())
+            {
+                *domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            }
+            rule.regexes = rule
+                .domain_regex
+                .iter()
+                .map(|r| Regex::new(r).map_err(|e| Error::RustError(format!("invalid domain regex {}: {}", r, e))))
+                .collect::<Result<_>>()?;
+        }
+        Ok(table)
+    }
+
+    // first matching rule wins
+    pub fn route(&self, dest: &Destination) -> Option<&Action> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(dest))
+            .map(|rule| &rule.action)
+            .or(self.default.as_ref())
+    }
+
+    pub fn group(&self, name: &str) -> Option<&Vec<String>> {
+        self.groups.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dest(addr: &str, port: u16) -> Destination<'_> {
+        Destination {
+            addr,
+            port,
+            protocol: Protocol::Vless,
+            user: None,
+        }
+    }
+
+    #[test]
+    fn test_route() {
+        let table = RouteTable::parse(
+            r#"{
+                "rules": [
+                    { "domain_suffix": ["Example.com."], "port": ["80", "8000-9000"], "action": "direct" },
+                    { "domain_regex": ["^ads?\\."], "action": "block" },
+                    { "ip_cidr": ["10.0.0.0/8"], "protocol": ["trojan"], "action": "block" },
+                    { "ip_cidr": ["1.0.0.0/8"], "action": { "group": "sg" } }
+                ],
+                "groups": { "sg": ["1.2.3.4:443"] }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(table.route(&dest("www.example.com", 8080)), Some(&Action::Direct));
+        assert_eq!(table.route(&dest("example.com", 80)), Some(&Action::Direct));
+        assert_eq!(table.route(&dest("notexample.com", 80)), None);
+        assert_eq!(table.route(&dest("www.example.com", 443)), None);
+        assert_eq!(table.route(&dest("ad.example.org", 443)), Some(&Action::Block));
+        assert_eq!(table.route(&dest("10.1.1.1", 443)), None);
+        assert_eq!(table.route(&dest("1.1.1.1", 443)), Some(&Action::Group("sg".to_string())));
+        assert_eq!(parse_host_port("[::1]:443"), Some(("::1".to_string(), 443)));
+    }
+}
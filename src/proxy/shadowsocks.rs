@@ -1,33 +1,406 @@
+use super::codec::Codec;
 use super::ProxyStream;
-use crate::common::{parse_addr, parse_port};
+use crate::common::aead::{AeadCipher, NONCE_LEN, TAG_LEN};
+use crate::common::{parse_addr, parse_port, replay::ReplayFilter, unix_timestamp};
+use crate::config::{Protocol, User};
+use std::sync::Mutex;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bytes::{BufMut, BytesMut};
+use hkdf::Hkdf;
+use md5::{Digest, Md5};
+use once_cell::sync::Lazy;
+use sha1::Sha1;
+use sha2::Sha256;
+use tokio::io::AsyncReadExt;
 use worker::*;
 
+// https://shadowsocks.org/doc/aead.html
+const MAX_PAYLOAD_SIZE: usize = 0x3fff;
+const SUBKEY_INFO: &[u8] = b"ss-subkey";
+
+// https://github.com/Shadowsocks-NET/shadowsocks-specs/blob/main/2022-1-shadowsocks-2022-edition.md
+const MAX_PAYLOAD_SIZE_2022: usize = 0xffff;
+const SUBKEY_CONTEXT_2022: &str = "shadowsocks 2022 session subkey";
+const HEADER_TYPE_CLIENT_STREAM: u8 = 0;
+const HEADER_TYPE_SERVER_STREAM: u8 = 1;
+// allowed clock skew between client and server, in seconds
+const TIMESTAMP_WINDOW_2022: u64 = 30;
+static SALT_FILTER_2022: Lazy<Mutex<ReplayFilter>> =
+    Lazy::new(|| Mutex::new(ReplayFilter::new(8192, TIMESTAMP_WINDOW_2022 * 2)));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20IetfPoly1305,
+    Blake3Aes128Gcm,
+    Blake3Aes256Gcm,
+}
+
+impl Method {
+    pub const ALL: [Method; 5] = [
+        Method::Aes128Gcm,
+        Method::Aes256Gcm,
+        Method::ChaCha20IetfPoly1305,
+        Method::Blake3Aes128Gcm,
+        Method::Blake3Aes256Gcm,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Method::Aes128Gcm => "aes-128-gcm",
+            Method::Aes256Gcm => "aes-256-gcm",
+            Method::ChaCha20IetfPoly1305 => "chacha20-ietf-poly1305",
+            Method::Blake3Aes128Gcm => "2022-blake3-aes-128-gcm",
+            Method::Blake3Aes256Gcm => "2022-blake3-aes-256-gcm",
+        }
+    }
+
+    // salt length is always equal to the key length
+    pub fn key_len(&self) -> usize {
+        match self {
+            Method::Aes128Gcm | Method::Blake3Aes128Gcm => 16,
+            Method::Aes256Gcm | Method::ChaCha20IetfPoly1305 | Method::Blake3Aes256Gcm => 32,
+        }
+    }
+
+    pub fn is_2022(&self) -> bool {
+        matches!(self, Method::Blake3Aes128Gcm | Method::Blake3Aes256Gcm)
+    }
+
+    // plaintext length of the first chunk: the payload length for legacy
+    // methods, the fixed-length request header for 2022
+    fn header_len(&self) -> usize {
+        if self.is_2022() {
+            1 + 8 + 2
+        } else {
+            2
+        }
+    }
+
+    fn max_payload_size(&self) -> usize {
+        if self.is_2022() {
+            MAX_PAYLOAD_SIZE_2022
+        } else {
+            MAX_PAYLOAD_SIZE
+        }
+    }
+
+    fn key(&self, password: &str) -> Vec<u8> {
+        if self.is_2022() {
+            derive_psk(password, self.key_len())
+        } else {
+            derive_key(password, self.key_len())
+        }
+    }
+
+    fn cipher(&self, key: &[u8], salt: &[u8]) -> AeadCipher {
+        let mut subkey = vec![0u8; self.key_len()];
+        if self.is_2022() {
+            let material = [key, salt].concat();
+            let derived = blake3::derive_key(SUBKEY_CONTEXT_2022, &material);
+            subkey.copy_from_slice(&derived[..self.key_len()]);
+        } else {
+            Hkdf::<Sha1>::new(Some(salt), key)
+                .expand(SUBKEY_INFO, &mut subkey)
+                .expect("subkey length is valid for hkdf-sha1");
+        }
+
+        match self {
+            Method::Aes128Gcm | Method::Blake3Aes128Gcm => AeadCipher::aes_128_gcm(&subkey),
+            Method::Aes256Gcm | Method::Blake3Aes256Gcm => AeadCipher::aes_256_gcm(&subkey),
+            Method::ChaCha20IetfPoly1305 => AeadCipher::chacha20_poly1305(&subkey),
+        }
+    }
+}
+
+// EVP_BytesToKey with md5, as used by every shadowsocks implementation
+fn derive_key(password: &str, key_len: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(key_len + 16);
+    let mut prev: Vec<u8> = Vec::new();
+    while key.len() < key_len {
+        let digest = crate::md5!(&prev, password.as_bytes());
+        key.extend_from_slice(&digest);
+        prev = digest.to_vec();
+    }
+    key.truncate(key_len);
+    key
+}
+
+// 2022 methods need a raw base64 key of exactly the cipher key length; other
+// passwords are stretched with sha256 so plain user credentials still work
+pub fn derive_psk(password: &str, key_len: usize) -> Vec<u8> {
+    match STANDARD.decode(password) {
+        Ok(psk) if psk.len() == key_len => psk,
+        _ => crate::sha256!(password.as_bytes())[..key_len].to_vec(),
+    }
+}
+
+fn increment_nonce(nonce: &mut [u8; NONCE_LEN]) {
+    for b in nonce.iter_mut() {
+        *b = b.wrapping_add(1);
+        if *b != 0 {
+            break;
+        }
+    }
+}
+
+//  +--------------+---------------+--------------+------------+
+//  |  *DataLen*   |  DataLen_TAG  |    *Data*    |  Data_TAG  |
+//  +--------------+---------------+--------------+------------+
+//  |      2       |     Fixed     |   Variable   |   Fixed    |
+//  +--------------+---------------+--------------+------------+
+pub struct ShadowsocksCodec {
+    method: Method,
+    key: Vec<u8>,
+    decoder: AeadCipher,
+    decoder_nonce: [u8; NONCE_LEN],
+    pending_len: Option<usize>,
+    encoder: Option<(AeadCipher, [u8; NONCE_LEN])>,
+    // request salt and timestamp echoed in the 2022 response header
+    request: Option<(Vec<u8>, u64)>,
+}
+
+impl ShadowsocksCodec {
+    pub fn new(method: Method, key: Vec<u8>, salt: &[u8]) -> Self {
+        let decoder = method.cipher(&key, salt);
+
+        Self {
+            method,
+            key,
+            decoder,
+            decoder_nonce: [0u8; NONCE_LEN],
+            pending_len: None,
+            encoder: None,
+            request: None,
+        }
+    }
+
+    fn open(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        let plain = self.decoder.decrypt(&self.decoder_nonce, chunk)?;
+        increment_nonce(&mut self.decoder_nonce);
+        Ok(plain)
+    }
+}
+
+impl Codec for ShadowsocksCodec {
+    fn decode(&mut self, src: &mut BytesMut, dst: &mut BytesMut) -> std::io::Result<()> {
+        loop {
+            match self.pending_len {
+                None => {
+                    if src.len() < 2 + TAG_LEN {
+                        return Ok(());
+                    }
+                    let len = self.open(&src.split_to(2 + TAG_LEN))?;
+                    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                    if len > self.method.max_payload_size() {
+                        return Err(std::io::Error::other(format!("shadowsocks chunk of {} bytes is too large", len)));
+                    }
+                    self.pending_len = Some(len);
+                }
+                Some(len) => {
+                    if src.len() < len + TAG_LEN {
+                        return Ok(());
+                    }
+                    let payload = self.open(&src.split_to(len + TAG_LEN))?;
+                    dst.put_slice(&payload);
+                    self.pending_len = None;
+                }
+            }
+        }
+    }
+
+    fn encode(&mut self, mut src: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(src.len() + 64);
+
+        // the response stream starts with our own salt
+        if self.encoder.is_none() {
+            let mut salt = vec![0u8; self.method.key_len()];
+            getrandom::getrandom(&mut salt).map_err(|e| std::io::Error::other(e.to_string()))?;
+            self.encoder = Some((self.method.cipher(&self.key, &salt), [0u8; NONCE_LEN]));
+            out.extend_from_slice(&salt);
+
+            // +------+-----------+--------------+--------+
+            // | type | timestamp | request salt | length |
+            // +------+-----------+--------------+--------+
+            // |  1B  |   u64be   |   16/32B     | u16be  |
+            // +------+-----------+--------------+--------+
+            if let (Some((request_salt, timestamp)), Some((cipher, nonce))) =
+                (self.request.as_ref(), self.encoder.as_mut())
+            {
+                let (first, _) = src.split_at(src.len().min(MAX_PAYLOAD_SIZE_2022));
+                let mut header = vec![HEADER_TYPE_SERVER_STREAM];
+                header.extend_from_slice(&timestamp.to_be_bytes());
+                header.extend_from_slice(request_salt);
+                header.extend_from_slice(&(first.len() as u16).to_be_bytes());
+
+                out.extend(cipher.encrypt(nonce, &header)?);
+                increment_nonce(nonce);
+                out.extend(cipher.encrypt(nonce, first)?);
+                increment_nonce(nonce);
+                src = &src[first.len()..];
+            }
+        }
+
+        if let Some((cipher, nonce)) = self.encoder.as_mut() {
+            for chunk in src.chunks(self.method.max_payload_size()) {
+                out.extend(cipher.encrypt(nonce, &(chunk.len() as u16).to_be_bytes())?);
+                increment_nonce(nonce);
+                out.extend(cipher.encrypt(nonce, chunk)?);
+                increment_nonce(nonce);
+            }
+        }
+
+        Ok(out)
+    }
+}
+
 impl <'a> ProxyStream<'a> {
+    // finds the user and method whose key opens the first length chunk
+    fn shadowsocks_user(&self, buffer: &[u8]) -> Option<(User, Method, Vec<u8>)> {
+        for user in self.config.users_for(Protocol::Shadowsocks) {
+            let password = user.password();
+            for method in Method::ALL {
+                let salt_len = method.key_len();
+                let chunk_len = method.header_len() + TAG_LEN;
+                if buffer.len() < salt_len + chunk_len {
+                    continue;
+                }
+
+                let key = method.key(&password);
+                let cipher = method.cipher(&key, &buffer[..salt_len]);
+                if cipher
+                    .decrypt(&[0u8; NONCE_LEN], &buffer[salt_len..salt_len + chunk_len])
+                    .is_ok()
+                {
+                    return Some((user.clone(), method, key));
+                }
+            }
+        }
+        None
+    }
+
+    pub fn is_shadowsocks(&self, buffer: &[u8]) -> bool {
+        self.shadowsocks_user(buffer).is_some()
+    }
+
     pub async fn process_shadowsocks(&mut self) -> Result<()> {
+        let (user, method, key) = self
+            .shadowsocks_user(&self.buffer)
+            .ok_or_else(|| Error::RustError("invalid shadowsocks credential".to_string()))?;
+        console_log!("shadowsocks user: {} ({})", user.label, method.name());
+        self.user = Some(user);
+
+        // read salt, everything after it goes through the chunk decoder
+        let mut salt = vec![0u8; method.key_len()];
+        self.read_exact(&mut salt).await?;
+        let mut codec = ShadowsocksCodec::new(method, key, &salt);
+
+        // +------+-----------+--------+
+        // | type | timestamp | length |
+        // +------+-----------+--------+
+        // |  1B  |   u64be   | u16be  |
+        // +------+-----------+--------+
+        if method.is_2022() {
+            let mut header = [0u8; 1 + 8 + 2 + TAG_LEN];
+            self.read_exact(&mut header).await?;
+            let header = codec.open(&header)?;
+            if header[0] != HEADER_TYPE_CLIENT_STREAM {
+                return Err(Error::RustError("invalid shadowsocks 2022 header type".to_string()));
+            }
+
+            let mut timestamp = [0u8; 8];
+            timestamp.copy_from_slice(&header[1..9]);
+            let timestamp = u64::from_be_bytes(timestamp);
+            let now = unix_timestamp();
+            if now.abs_diff(timestamp) > TIMESTAMP_WINDOW_2022 {
+                return Err(Error::RustError("shadowsocks 2022 timestamp out of range".to_string()));
+            }
+            if !SALT_FILTER_2022.lock().unwrap().check(&salt, now) {
+                return Err(Error::RustError("replayed shadowsocks 2022 salt".to_string()));
+            }
+
+            codec.pending_len = Some(u16::from_be_bytes([header[9], header[10]]) as usize);
+            codec.request = Some((salt, now));
+        }
+        self.codec = Some(Box::new(codec));
+
         // read port and address
         let remote_addr = parse_addr(self).await?;
         let remote_port = parse_port(self).await?;
-        
+
+        // skip the variable-length padding
+        if method.is_2022() {
+            let padding_len = self.read_u16().await?;
+            let mut padding = vec![0u8; padding_len as _];
+            self.read_exact(&mut padding).await?;
+        }
+
         let is_tcp = true; // difficult to detect udp packet from shadowsocks
-        
+
         if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
-
-            // send header
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
+            if let Err(e) = self.handle_tcp(Protocol::Shadowsocks, remote_addr, remote_port).await {
+                console_error!("error handling tcp: {}", e)
             }
         } else {
-            if let Err(e) = self.handle_udp_outbound().await {
+            if let Err(e) = self.handle_udp_outbound(remote_port).await {
                 console_error!("error handling udp: {}", e)
             }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_key() {
+        // openssl enc -aes-256-cbc -k foobar -nosalt -md md5 -P
+        assert_eq!(
+            derive_key("foobar", 32),
+            [
+                0x38, 0x58, 0xf6, 0x22, 0x30, 0xac, 0x3c, 0x91, 0x5f, 0x30, 0x0c, 0x66, 0x43, 0x12,
+                0xc6, 0x3f, 0x56, 0x83, 0x78, 0x52, 0x96, 0x14, 0xd2, 0x2d, 0xdb, 0x49, 0x23, 0x7d,
+                0x2f, 0x60, 0xbf, 0xdf,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_derive_psk() {
+        let psk = [7u8; 16];
+        assert_eq!(derive_psk(&STANDARD.encode(psk), 16), psk);
+        assert_eq!(derive_psk("not a psk", 16).len(), 16);
+    }
+
+    #[test]
+    fn test_codec_roundtrip() {
+        let key = derive_key("password", 32);
+        let mut server = ShadowsocksCodec::new(Method::Aes256Gcm, key.clone(), &[0u8; 32]);
+        let encoded = server.encode(b"hello world").unwrap();
+
+        let (salt, chunks) = encoded.split_at(32);
+        let mut client = ShadowsocksCodec::new(Method::Aes256Gcm, key, salt);
+        let mut src = BytesMut::from(chunks);
+        let mut dst = BytesMut::new();
+        client.decode(&mut src, &mut dst).unwrap();
+
+        assert_eq!(&dst[..], b"hello world");
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_decode_oversized_chunk() {
+        let key = derive_key("password", 32);
+        let salt = [0u8; 32];
+        let cipher = Method::Aes256Gcm.cipher(&key, &salt);
+        let len = ((MAX_PAYLOAD_SIZE + 1) as u16).to_be_bytes();
+        let mut src = BytesMut::from(&cipher.encrypt(&[0u8; NONCE_LEN], &len).unwrap()[..]);
+
+        let mut codec = ShadowsocksCodec::new(Method::Aes256Gcm, key, &salt);
+        assert!(codec.decode(&mut src, &mut BytesMut::new()).is_err());
+    }
+}
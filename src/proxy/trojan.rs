@@ -1,20 +1,108 @@
 use super::ProxyStream;
-use tokio::io::AsyncReadExt;
+use crate::config::Protocol;
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use crate::common::{parse_addr, parse_port};
+use sha2::{Digest, Sha224};
+use subtle::ConstantTimeEq;
 use worker::*;
 
+// hex(SHA224(password)), as sent by trojan clients
+fn password_hash(password: &str) -> [u8; 56] {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    let digest = Sha224::digest(password.as_bytes());
+    let mut hash = [0u8; 56];
+    for (i, b) in digest.iter().enumerate() {
+        hash[i * 2] = HEX[(b >> 4) as usize];
+        hash[i * 2 + 1] = HEX[(b & 0x0f) as usize];
+    }
+    hash
+}
+
+// socks5 style ATYP, address and port, as used in trojan udp packets
+fn encode_addr(addr: &str, port: u16) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + 1 + addr.len() + 2);
+    match addr.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            buf.push(1);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            buf.push(4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            buf.push(3);
+            buf.push(addr.len() as u8);
+            buf.extend_from_slice(addr.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&port.to_be_bytes());
+    buf
+}
+
 impl <'a> ProxyStream<'a> {
+    // https://trojan-gfw.github.io/trojan/protocol
+    //
+    // +------+----------+----------+--------+---------+----------+
+    // | ATYP | DST.ADDR | DST.PORT | Length |  CRLF   | Payload  |
+    // +------+----------+----------+--------+---------+----------+
+    // |  1   | Variable |    2     |   2    | X'0D0A' | Variable |
+    // +------+----------+----------+--------+---------+----------+
+    async fn handle_trojan_udp(&mut self) -> Result<()> {
+        loop {
+            self.fill_buffer_until(1).await?;
+            if self.buffer.is_empty() {
+                break;
+            }
+
+            let remote_addr = parse_addr(self).await?;
+            let remote_port = parse_port(self).await?;
+            let len = self.read_u16().await?;
+            self.read_u16().await?;
+            let mut payload = vec![0u8; len as _];
+            self.read_exact(&mut payload).await?;
+
+            if remote_port != 53 {
+                console_error!("dropping trojan udp packet to {}:{}, only dns is supported", remote_addr, remote_port);
+                continue;
+            }
+
+            let answer = crate::dns::doh(&self.config.dns, &payload).await?;
+            let mut packet = encode_addr(&remote_addr, remote_port);
+            packet.extend_from_slice(&(answer.len() as u16).to_be_bytes());
+            packet.extend_from_slice(b"\r\n");
+            packet.extend_from_slice(&answer);
+            self.write_all(&packet).await?;
+        }
+
+        Ok(())
+    }
+
     pub async fn process_trojan(&mut self) -> Result<()> {
-        // ignore user_id
-        let mut _user_id = [0u8; 56];
-        self.read_exact(&mut _user_id).await?;
+        // read and verify user_id
+        let mut user_id = [0u8; 56];
+        self.read_exact(&mut user_id).await?;
+        let user = self
+            .config
+            .users_for(Protocol::Trojan)
+            .find(|user| bool::from(user_id.ct_eq(&password_hash(&user.password()))))
+            .cloned()
+            .ok_or_else(|| Error::RustError("invalid trojan password".to_string()))?;
+        console_log!("trojan user: {}", user.label);
+        self.user = Some(user);
 
         // remove crlf
         self.read_u16().await?;
         
-        // read instruction
+        // read instruction: 1 is CONNECT, 3 is UDP ASSOCIATE
         let network_type = self.read_u8().await?;
-        let is_tcp = network_type == 1;
+        let is_tcp = match network_type {
+            1 => true,
+            3 => false,
+            _ => return Err(Error::RustError(format!("invalid trojan command: {}", network_type))),
+        };
 
         // read port and address
         let remote_addr = parse_addr(self).await?;
@@ -24,23 +112,34 @@ impl <'a> ProxyStream<'a> {
         self.read_u16().await?;
 
         if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
-
-            // send header
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
+            if let Err(e) = self.handle_tcp(Protocol::Trojan, remote_addr, remote_port).await {
+                console_error!("error handling tcp: {}", e)
             }
         } else {
-            if let Err(e) = self.handle_udp_outbound().await {
+            if let Err(e) = self.handle_trojan_udp().await {
                 console_error!("error handling udp: {}", e)
             }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_addr() {
+        assert_eq!(encode_addr("1.1.1.1", 53), [1, 1, 1, 1, 1, 0, 53]);
+        assert_eq!(encode_addr("a.io", 53), [3, 4, b'a', b'.', b'i', b'o', 0, 53]);
+    }
+
+    #[test]
+    fn test_password_hash() {
+        assert_eq!(
+            &password_hash("abc")[..],
+            b"23097d223405d8228642a477bda255b32aadbce4bda0b3f7e36c9da7"
+        );
+    }
+}
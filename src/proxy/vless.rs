@@ -1,5 +1,7 @@
 use super::ProxyStream;
+use crate::config::Protocol;
 use crate::common::{parse_addr, parse_port};
+use subtle::ConstantTimeEq;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use uuid::Uuid;
 use worker::*;
@@ -9,10 +11,18 @@ impl <'a> ProxyStream<'a> {
         // ignore version
         self.read_u8().await?;
         
-        // read uuid
+        // read and verify uuid
         let mut user_id = [0u8; 16];
         self.read_exact(&mut user_id).await?;
-        let _uuid = Uuid::from_bytes(user_id);
+        let uuid = Uuid::from_bytes(user_id);
+        let user = self
+            .config
+            .users_for(Protocol::Vless)
+            .find(|user| bool::from(user.id.as_bytes().ct_eq(&user_id)))
+            .cloned()
+            .ok_or_else(|| Error::RustError(format!("invalid vless user id: {}", uuid)))?;
+        console_log!("vless user: {}", user.label);
+        self.user = Some(user);
         
         // read protobuf
         let m_len = self.read_u8().await?;
@@ -27,21 +37,15 @@ impl <'a> ProxyStream<'a> {
         let remote_port = parse_port(self).await?;
         let remote_addr = parse_addr(self).await?;
 
-        if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
+        // send header
+        self.write_all(&[0u8; 2]).await?;
 
-            // send header
-            self.write(&[0u8; 2]).await?;
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
+        if is_tcp {
+            if let Err(e) = self.handle_tcp(Protocol::Vless, remote_addr, remote_port).await {
+                console_error!("error handling tcp: {}", e)
             }
         } else {
-            if let Err(e) = self.handle_udp_outbound().await {
+            if let Err(e) = self.handle_udp_outbound(remote_port).await {
                 console_error!("error handling udp: {}", e)
             }
         }
@@ -1,26 +1,321 @@
+use super::codec::Codec;
 use super::ProxyStream;
+use crate::common::aead::{AeadCipher, NONCE_LEN, TAG_LEN};
+use crate::config::Protocol;
 use crate::common::{
-    hash, parse_port, parse_addr, KDFSALT_CONST_AEAD_RESP_HEADER_IV, KDFSALT_CONST_AEAD_RESP_HEADER_KEY, KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV, KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY
+    hash, parse_port, parse_addr, replay::ReplayFilter, unix_timestamp, KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY, KDFSALT_CONST_AEAD_RESP_HEADER_IV, KDFSALT_CONST_AEAD_RESP_HEADER_KEY, KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV, KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY
 };
 use std::io::Cursor;
-use aes::cipher::KeyInit;
+use std::sync::Mutex;
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::{Aes128, Block};
 use aes_gcm::{
     aead::{Aead, Payload},
     Aes128Gcm,
 };
+use bytes::{BufMut, BytesMut};
 use md5::{Digest, Md5};
 use sha2::Sha256;
+use sha3::digest::{ExtendableOutput, XofReader};
+use sha3::{Shake128, Shake128Reader};
+use once_cell::sync::Lazy;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use worker::*;
 
+// allowed clock skew between client and server, in seconds
+const AUTH_ID_WINDOW: u64 = 120;
+static AUTH_ID_FILTER: Lazy<Mutex<ReplayFilter>> =
+    Lazy::new(|| Mutex::new(ReplayFilter::new(8192, AUTH_ID_WINDOW * 2)));
+
+// https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/aead/authid.go
+//
+// +-------------------+-------------------+-------------------+
+// |     Timestamp     |       Random      |       CRC32       |
+// +-------------------+-------------------+-------------------+
+// |      8 Bytes      |      4 Bytes      |      4 Bytes      |
+// +-------------------+-------------------+-------------------+
+fn decrypt_auth_id(key: &[u8], auth_id: &[u8; 16]) -> Option<u64> {
+    let auth_id_key = &hash::kdf(key, &[KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY])[..16];
+    let mut block = Block::clone_from_slice(auth_id);
+    Aes128::new(auth_id_key.into()).decrypt_block(&mut block);
+
+    let checksum = u32::from_be_bytes([block[12], block[13], block[14], block[15]]);
+    if crc32fast::hash(&block[..12]) != checksum {
+        return None;
+    }
+
+    let mut timestamp = [0u8; 8];
+    timestamp.copy_from_slice(&block[..8]);
+    Some(u64::from_be_bytes(timestamp))
+}
+
+// rejects auth ids outside the allowed clock skew and ones seen before
+fn verify_auth_id(filter: &mut ReplayFilter, auth_id: &[u8; 16], timestamp: u64, now: u64) -> Result<()> {
+    if now.abs_diff(timestamp) > AUTH_ID_WINDOW {
+        return Err(Error::RustError("auth id timestamp out of range".to_string()));
+    }
+    if !filter.check(auth_id, now) {
+        return Err(Error::RustError("replayed auth id".to_string()));
+    }
+    Ok(())
+}
+
+// https://github.com/v2fly/v2ray-core/blob/master/common/protocol/headers.proto
+const SECURITY_TYPE_AUTO: u8 = 0x02;
+const SECURITY_TYPE_AES128_GCM: u8 = 0x03;
+const SECURITY_TYPE_CHACHA20_POLY1305: u8 = 0x04;
+const SECURITY_TYPE_NONE: u8 = 0x05;
+const SECURITY_TYPE_ZERO: u8 = 0x06;
+
+const OPTION_CHUNK_STREAM: u8 = 0x01;
+const OPTION_CHUNK_MASKING: u8 = 0x04;
+const OPTION_GLOBAL_PADDING: u8 = 0x08;
+const OPTION_AUTHENTICATED_LENGTH: u8 = 0x10;
+
+const KDFSALT_CONST_AUTH_LEN: &[u8] = b"auth_len";
+const MAX_PADDING_SIZE: u16 = 64;
+
+const MAX_CHUNK_SIZE: usize = 8192;
+
+// https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/encoding/auth.go
+//
+// +-------------------+-------------------+-------------------+
+// |   Chunk Length    |   Encrypted Data  |        Tag        |
+// +-------------------+-------------------+-------------------+
+// |      2 Bytes      |     N Bytes       |  16 Bytes or none |
+// +-------------------+-------------------+-------------------+
+struct ChunkCipher {
+    // none means the "none" security type: chunks are framed but not encrypted
+    cipher: Option<AeadCipher>,
+    iv: [u8; 16],
+    count: u16,
+}
+
+impl ChunkCipher {
+    fn new(security: u8, key: &[u8], iv: &[u8]) -> Result<Self> {
+        let cipher = match security {
+            // clients resolve "auto" before sending, treat it as aes-128-gcm
+            SECURITY_TYPE_AUTO | SECURITY_TYPE_AES128_GCM => Some(AeadCipher::aes_128_gcm(key)),
+            SECURITY_TYPE_CHACHA20_POLY1305 => {
+                let first = crate::md5!(key);
+                let second = crate::md5!(&first);
+                Some(AeadCipher::chacha20_poly1305(&[first, second].concat()))
+            }
+            SECURITY_TYPE_NONE | SECURITY_TYPE_ZERO => None,
+            _ => {
+                return Err(Error::RustError(format!("unsupported vmess security type: {}", security)));
+            }
+        };
+
+        let mut chunk_iv = [0u8; 16];
+        chunk_iv.copy_from_slice(&iv[..16]);
+        Ok(Self {
+            cipher,
+            iv: chunk_iv,
+            count: 0,
+        })
+    }
+
+    fn overhead(&self) -> usize {
+        if self.cipher.is_some() {
+            TAG_LEN
+        } else {
+            0
+        }
+    }
+
+    // count (2 bytes, big endian) followed by iv[2..12]
+    fn next_nonce(&mut self) -> [u8; NONCE_LEN] {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&self.iv[..NONCE_LEN]);
+        nonce[..2].copy_from_slice(&self.count.to_be_bytes());
+        self.count = self.count.wrapping_add(1);
+        nonce
+    }
+
+    fn seal(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&nonce, data),
+            None => Ok(data.to_vec()),
+        }
+    }
+
+    fn open(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(&nonce, data),
+            None => Ok(data.to_vec()),
+        }
+    }
+}
+
+// one direction of the chunk stream, configured by the request options
+struct ChunkStream {
+    body: ChunkCipher,
+    // authenticated length: the chunk length is sealed with its own key
+    length: Option<ChunkCipher>,
+    // chunk masking: SHAKE128(iv) masks the length and drives global padding
+    mask: Option<Shake128Reader>,
+    padding: bool,
+}
+
+impl ChunkStream {
+    fn new(options: u8, security: u8, key: &[u8], iv: &[u8]) -> Result<Self> {
+        let body = ChunkCipher::new(security, key, iv)?;
+
+        let length = if options & OPTION_AUTHENTICATED_LENGTH != 0 && body.cipher.is_some() {
+            let length_key = &hash::kdf(key, &[KDFSALT_CONST_AUTH_LEN])[..16];
+            Some(ChunkCipher::new(security, length_key, iv)?)
+        } else {
+            None
+        };
+
+        let mask = if options & OPTION_CHUNK_MASKING != 0 {
+            let mut shake = Shake128::default();
+            sha3::digest::Update::update(&mut shake, iv);
+            Some(shake.finalize_xof())
+        } else {
+            None
+        };
+
+        let padding = options & OPTION_GLOBAL_PADDING != 0;
+        if padding && mask.is_none() {
+            return Err(Error::RustError("vmess global padding requires chunk masking".to_string()));
+        }
+
+        Ok(Self {
+            body,
+            length,
+            mask,
+            padding,
+        })
+    }
+
+    fn next_mask(&mut self) -> u16 {
+        let mut mask = [0u8; 2];
+        if let Some(shake) = self.mask.as_mut() {
+            shake.read(&mut mask);
+        }
+        u16::from_be_bytes(mask)
+    }
+
+    // must be drawn before the length mask of the same chunk
+    fn next_padding(&mut self) -> usize {
+        if self.padding {
+            (self.next_mask() % MAX_PADDING_SIZE) as usize
+        } else {
+            0
+        }
+    }
+
+    fn size_len(&self) -> usize {
+        2 + self.length.as_ref().map_or(0, |length| length.overhead())
+    }
+
+    // returns the length of the sealed data plus padding
+    fn decode_size(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if let Some(length) = self.length.as_mut() {
+            let size = length.open(data)?;
+            return Ok(u16::from_be_bytes([size[0], size[1]]) as usize + self.body.overhead());
+        }
+
+        let size = u16::from_be_bytes([data[0], data[1]]);
+        if self.mask.is_some() {
+            return Ok((size ^ self.next_mask()) as usize);
+        }
+        Ok(size as usize)
+    }
+
+    fn encode_size(&mut self, size: usize) -> std::io::Result<Vec<u8>> {
+        if size > u16::MAX as usize {
+            return Err(std::io::Error::other("vmess chunk too large"));
+        }
+        if let Some(length) = self.length.as_mut() {
+            let size = (size - self.body.overhead()) as u16;
+            return length.seal(&size.to_be_bytes());
+        }
+
+        let mut size = size as u16;
+        if self.mask.is_some() {
+            size ^= self.next_mask();
+        }
+        Ok(size.to_be_bytes().to_vec())
+    }
+}
+
+pub struct VmessCodec {
+    request: ChunkStream,
+    response: ChunkStream,
+    // sealed length and padding length of the chunk being read
+    pending: Option<(usize, usize)>,
+    // udp: every chunk is one datagram, decoded with a u16be length prefix
+    // and encoded from a single write
+    packet: bool,
+}
+
+impl VmessCodec {
+    fn new(options: u8, security: u8, request_key: &[u8], request_iv: &[u8], response_key: &[u8], response_iv: &[u8]) -> Result<Self> {
+        Ok(Self {
+            request: ChunkStream::new(options, security, request_key, request_iv)?,
+            response: ChunkStream::new(options, security, response_key, response_iv)?,
+            pending: None,
+            packet: false,
+        })
+    }
+}
+
+impl Codec for VmessCodec {
+    fn decode(&mut self, src: &mut BytesMut, dst: &mut BytesMut) -> std::io::Result<()> {
+        loop {
+            match self.pending {
+                None => {
+                    let size_len = self.request.size_len();
+                    if src.len() < size_len {
+                        return Ok(());
+                    }
+                    let padding = self.request.next_padding();
+                    let size = self.request.decode_size(&src.split_to(size_len))?;
+                    self.pending = Some((size, padding));
+                }
+                Some((size, padding)) => {
+                    if src.len() < size {
+                        return Ok(());
+                    }
+                    let chunk = src.split_to(size);
+                    if size < padding + self.request.body.overhead() {
+                        return Err(std::io::Error::other("vmess chunk too short"));
+                    }
+                    let payload = self.request.body.open(&chunk[..size - padding])?;
+                    if self.packet {
+                        dst.put_u16(payload.len() as u16);
+                    }
+                    dst.put_slice(&payload);
+                    self.pending = None;
+                }
+            }
+        }
+    }
+
+    fn encode(&mut self, src: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(src.len() + 128);
+        let chunk_size = if self.packet { src.len().max(1) } else { MAX_CHUNK_SIZE };
+        for chunk in src.chunks(chunk_size) {
+            let padding = self.response.next_padding();
+            let sealed = self.response.body.seal(chunk)?;
+            out.extend(self.response.encode_size(sealed.len() + padding)?);
+            out.extend(sealed);
+
+            let mut padding = vec![0u8; padding];
+            getrandom::getrandom(&mut padding).map_err(|e| std::io::Error::other(e.to_string()))?;
+            out.extend(padding);
+        }
+        Ok(out)
+    }
+}
 
 impl <'a> ProxyStream<'a> {
     async fn aead_decrypt(&mut self) -> Result<Vec<u8>> {
-        let key = crate::md5!(
-            &self.config.uuid.as_bytes(),
-            b"c48619fe-8f02-49e0-b9e9-edf763e17e21"
-        );
-
         // +-------------------+-------------------+-------------------+
         // |     Auth ID       |   Header Length   |       Nonce       |
         // +-------------------+-------------------+-------------------+
@@ -33,6 +328,23 @@ impl <'a> ProxyStream<'a> {
         let mut nonce = [0u8; 8];
         self.read_exact(&mut nonce).await?;
 
+        // the auth id checksum only matches under the connecting user's key
+        let (key, user, timestamp) = self
+            .config
+            .users_for(Protocol::Vmess)
+            .find_map(|user| {
+                let key = crate::md5!(
+                    &user.id.as_bytes(),
+                    b"c48619fe-8f02-49e0-b9e9-edf763e17e21"
+                );
+                decrypt_auth_id(&key, &auth_id).map(|timestamp| (key, user.clone(), timestamp))
+            })
+            .ok_or_else(|| Error::RustError("invalid vmess auth id".to_string()))?;
+
+        verify_auth_id(&mut AUTH_ID_FILTER.lock().unwrap(), &auth_id, timestamp, unix_timestamp())?;
+        console_log!("vmess user: {}", user.label);
+        self.user = Some(user);
+
         // https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/aead/kdf.go
         let header_length = {
             let header_length_key = &hash::kdf(
@@ -116,9 +428,10 @@ impl <'a> ProxyStream<'a> {
         let mut key = [0u8; 16];
         buf.read_exact(&mut key).await?;
 
-        // ignore options for now
+        // response authentication value, options, padding length and security
         let mut options = [0u8; 4];
         buf.read_exact(&mut options).await?;
+        let security = options[2] & 0x0f;
 
         let cmd = buf.read_u8().await?;
         let is_tcp = cmd == 0x1;
@@ -127,20 +440,20 @@ impl <'a> ProxyStream<'a> {
         let remote_addr = parse_addr(&mut buf).await?;
 
         // encrypt payload
-        let key = &crate::sha256!(&key)[..16];
-        let iv = &crate::sha256!(&iv)[..16];
+        let response_key = &crate::sha256!(&key)[..16];
+        let response_iv = &crate::sha256!(&iv)[..16];
 
         // https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L196
-        let length_key = &hash::kdf(&key, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY])[..16];
-        let length_iv = &hash::kdf(&iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV])[..12];
+        let length_key = &hash::kdf(response_key, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY])[..16];
+        let length_iv = &hash::kdf(response_iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV])[..12];
         let length = Aes128Gcm::new(length_key.into())
             // 4 bytes header: https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L238
             .encrypt(length_iv.into(), &4u16.to_be_bytes()[..])
             .map_err(|e| Error::RustError(e.to_string()))?;
-        self.write(&length).await?;
+        self.write_all(&length).await?;
 
-        let payload_key = &hash::kdf(&key, &[KDFSALT_CONST_AEAD_RESP_HEADER_KEY])[..16];
-        let payload_iv = &hash::kdf(&iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_IV])[..12];
+        let payload_key = &hash::kdf(response_key, &[KDFSALT_CONST_AEAD_RESP_HEADER_KEY])[..16];
+        let payload_iv = &hash::kdf(response_iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_IV])[..12];
         let header = {
             let header = [
                 options[0], // https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L242
@@ -150,25 +463,156 @@ impl <'a> ProxyStream<'a> {
                 .encrypt(payload_iv.into(), &header[..])
                 .map_err(|e| Error::RustError(e.to_string()))?
         };
-        self.write(&header).await?;
+        self.write_all(&header).await?;
 
-        if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
+        // body: chunk stream for aead/none security, raw stream for zero
+        if options[1] & OPTION_CHUNK_STREAM != 0 {
+            let mut codec = VmessCodec::new(options[1], security, &key, &iv, response_key, response_iv)?;
+            codec.packet = !is_tcp;
+            self.codec = Some(Box::new(codec));
+        } else if security != SECURITY_TYPE_NONE && security != SECURITY_TYPE_ZERO {
+            return Err(Error::RustError("vmess chunk stream required for aead security".to_string()));
+        } else if !is_tcp {
+            return Err(Error::RustError("vmess udp requires the chunk stream".to_string()));
+        }
 
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
+        if is_tcp {
+            if let Err(e) = self.handle_tcp(Protocol::Vmess, remote_addr, remote_port).await {
+                console_error!("error handling tcp: {}", e)
             }
         } else {
-            if let Err(e) = self.handle_udp_outbound().await {
+            if let Err(e) = self.handle_vmess_udp(remote_port).await {
                 console_error!("error handling udp: {}", e)
             }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    // answers the dns datagrams of a vmess udp session over doh, one chunk
+    // per datagram in both directions
+    async fn handle_vmess_udp(&mut self, port: u16) -> Result<()> {
+        if port != 53 {
+            return Err(Error::RustError(format!("udp is only supported for dns, not port {}", port)));
+        }
+
+        loop {
+            let len = match self.read_u16().await {
+                Ok(len) => len,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            let mut packet = vec![0u8; len as _];
+            self.read_exact(&mut packet).await?;
+
+            let answer = crate::dns::doh(&self.config.dns, &packet).await?;
+            self.write_all(&answer).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockEncrypt;
+
+    fn auth_id(key: &[u8], timestamp: u64) -> [u8; 16] {
+        let mut plain = [0u8; 16];
+        plain[..8].copy_from_slice(&timestamp.to_be_bytes());
+        plain[8..12].copy_from_slice(&[1, 2, 3, 4]);
+        let checksum = crc32fast::hash(&plain[..12]);
+        plain[12..].copy_from_slice(&checksum.to_be_bytes());
+
+        let auth_id_key = &hash::kdf(key, &[KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY])[..16];
+        let mut block = Block::clone_from_slice(&plain);
+        Aes128::new(auth_id_key.into()).encrypt_block(&mut block);
+        block.into()
+    }
+
+    #[test]
+    fn test_codec_roundtrip() {
+        let key = [1u8; 16];
+        let iv = [2u8; 16];
+        let options = [
+            OPTION_CHUNK_STREAM,
+            OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING | OPTION_GLOBAL_PADDING,
+            OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING | OPTION_GLOBAL_PADDING | OPTION_AUTHENTICATED_LENGTH,
+        ];
+        let securities = [SECURITY_TYPE_AES128_GCM, SECURITY_TYPE_CHACHA20_POLY1305, SECURITY_TYPE_NONE];
+        for (options, security) in options.into_iter().flat_map(|o| securities.map(|s| (o, s))) {
+            // the client's request stream mirrors our response stream
+            let mut server = VmessCodec::new(options, security, &key, &iv, &key, &iv).unwrap();
+            let mut client = VmessCodec::new(options, security, &key, &iv, &key, &iv).unwrap();
+
+            let payload = vec![7u8; MAX_CHUNK_SIZE + 100];
+            let mut src = BytesMut::from(&client.encode(&payload).unwrap()[..]);
+            let mut dst = BytesMut::new();
+            server.decode(&mut src, &mut dst).unwrap();
+
+            assert_eq!(&dst[..], &payload[..]);
+            assert!(src.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_packet_codec() {
+        let key = [1u8; 16];
+        let iv = [2u8; 16];
+        let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING | OPTION_GLOBAL_PADDING;
+        let mut server = VmessCodec::new(options, SECURITY_TYPE_AES128_GCM, &key, &iv, &key, &iv).unwrap();
+        let mut client = VmessCodec::new(options, SECURITY_TYPE_AES128_GCM, &key, &iv, &key, &iv).unwrap();
+        server.packet = true;
+        client.packet = true;
+
+        // two queries arriving in one websocket message stay two datagrams
+        let query = crate::dns::message::build_query("example.com", crate::dns::message::TYPE_A).unwrap();
+        let mut src = BytesMut::from(&client.encode(&query).unwrap()[..]);
+        src.extend_from_slice(&client.encode(&query).unwrap());
+        let mut dst = BytesMut::new();
+        server.decode(&mut src, &mut dst).unwrap();
+        for _ in 0..2 {
+            let len = u16::from_be_bytes([dst[0], dst[1]]) as usize;
+            let packet = dst.split_to(2 + len).split_off(2);
+            assert_eq!(&packet[..], &query[..]);
+            assert!(crate::dns::message::parse_question(&packet).is_ok());
+        }
+        assert!(dst.is_empty());
+
+        // an answer larger than a stream chunk still goes out as one chunk
+        let answer = vec![7u8; MAX_CHUNK_SIZE + 100];
+        let mut src = BytesMut::from(&server.encode(&answer).unwrap()[..]);
+        let mut dst = BytesMut::new();
+        client.decode(&mut src, &mut dst).unwrap();
+        assert_eq!(u16::from_be_bytes([dst[0], dst[1]]) as usize, answer.len());
+        assert_eq!(&dst[2..], &answer[..]);
+    }
+
+    #[test]
+    fn test_decrypt_auth_id() {
+        let uuid = uuid::uuid!("96850032-1b92-46e9-a4f2-b99631456894").as_bytes();
+        let key = crate::md5!(&uuid, b"c48619fe-8f02-49e0-b9e9-edf763e17e21");
+        let id = auth_id(&key, 1_700_000_000);
+
+        assert_eq!(decrypt_auth_id(&key, &id), Some(1_700_000_000));
+        assert_eq!(decrypt_auth_id(&[0u8; 16], &id), None);
+    }
+
+    #[test]
+    fn test_verify_auth_id() {
+        let uuid = uuid::uuid!("96850032-1b92-46e9-a4f2-b99631456894").as_bytes();
+        let key = crate::md5!(&uuid, b"c48619fe-8f02-49e0-b9e9-edf763e17e21");
+        let mut filter = ReplayFilter::new(16, AUTH_ID_WINDOW * 2);
+
+        let id = auth_id(&key, 1_700_000_000);
+        assert!(verify_auth_id(&mut filter, &id, 1_700_000_000, 1_700_000_121).is_err());
+        assert!(verify_auth_id(&mut filter, &id, 1_700_000_000, 1_699_999_879).is_err());
+        assert!(verify_auth_id(&mut filter, &id, 1_700_000_000, 1_700_000_100).is_ok());
+        // replayed
+        assert!(verify_auth_id(&mut filter, &id, 1_700_000_000, 1_700_000_101).is_err());
+
+        let id = auth_id(&key, 1_700_000_001);
+        assert!(verify_auth_id(&mut filter, &id, 1_700_000_001, 1_700_000_000).is_ok());
+    }
+}